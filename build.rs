@@ -6,5 +6,5 @@ fn main() -> Result<(), Box<dyn Error>> {
     //lalrpop::process_root()?;
     lalrpop::Configuration::new()
         .process_current_dir()?;
-    return Ok(());
+    Ok(())
 }
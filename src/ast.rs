@@ -1,4 +1,6 @@
 use num::rational::BigRational;
+use serde::{Deserialize, Serialize, Serializer};
+use std::ops::{Deref, DerefMut, Range};
 
 pub type Num = BigRational;
 
@@ -14,26 +16,180 @@ pub enum Node {
 }
 pub use Node::*;
 
-pub type Expr = Box<Node>;
+/// A byte range into the parsed input.
+pub type Span = Range<usize>;
 
-#[derive(Debug, PartialEq)]
+/// A node along with the span of input it was parsed from. Serializes
+/// as the node's fields plus `"span": {"start": .., "end": ..}`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Spanned<T> {
+    #[serde(flatten)]
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+impl<T> DerefMut for Spanned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.node
+    }
+}
+
+pub type Expr = Box<Spanned<Node>>;
+
+pub fn spanned(node: Node, span: Span) -> Expr {
+    Box::new(Spanned { node, span })
+}
+
+/// An expression with an empty span, for building trees by hand.
+pub fn expr(node: Node) -> Expr {
+    spanned(node, 0..0)
+}
+
+impl Node {
+    /// Whether `self` and `other` are the same tree, whatever spans
+    /// they were parsed from.
+    pub fn same_shape(&self, other: &Node) -> bool {
+        match (self, other) {
+            (Number(a, a_source), Number(b, b_source)) => a == b && a_source == b_source,
+            (Roll(a_count, a_sides), Roll(b_count, b_sides)) => (a_count, a_sides) == (b_count, b_sides),
+            (Var(a), Var(b)) => a == b,
+            (BinOp(a_op, a_left, a_right), BinOp(b_op, b_left, b_right)) =>
+                a_op == b_op && a_left.same_shape(b_left) && a_right.same_shape(b_right),
+            (UnaOp(a_op, a), UnaOp(b_op, b)) => a_op == b_op && a.same_shape(b),
+            (Funcall(a_name, a_args), Funcall(b_name, b_args)) =>
+                a_name == b_name && a_args.len() == b_args.len()
+                    && a_args.iter().zip(b_args).all(|(a, b)| a.same_shape(b)),
+            (BadParse(a), BadParse(b)) => a.same_shape(b),
+            _ => false,
+        }
+    }
+}
+
+/// Nodes serialize as objects tagged by `type`, with their children
+/// (themselves spanned) inline:
+///
+/// - `{"type": "number", "value": {"num": "3", "den": "2"}, "source": "digits"}`
+/// - `{"type": "roll", "count": 2, "sides": 6}`
+/// - `{"type": "var", "name": "x"}`
+/// - `{"type": "binOp", "op": "add", "left": .., "right": ..}`
+/// - `{"type": "unaOp", "op": "neg", "operand": ..}`
+/// - `{"type": "funcall", "name": "sqrt", "args": [..]}`
+/// - `{"type": "badParse", "expr": ..}`
+impl Serialize for Node {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match self {
+            Number(n, source) => NodeRepr::Number {
+                value: RationalRepr { num: n.numer().to_string(), den: n.denom().to_string() },
+                source: *source,
+            },
+            Roll(count, sides) => NodeRepr::Roll { count: *count, sides: *sides },
+            Var(name) => NodeRepr::Var { name },
+            BinOp(op, left, right) => NodeRepr::BinOp { op: *op, left, right },
+            UnaOp(op, operand) => NodeRepr::UnaOp { op: *op, operand },
+            Funcall(name, args) => NodeRepr::Funcall { name, args },
+            BadParse(expr) => NodeRepr::BadParse { expr },
+        };
+        repr.serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum NodeRepr<'a> {
+    Number { value: RationalRepr, source: NumSource },
+    Roll { count: i64, sides: i64 },
+    Var { name: &'a str },
+    BinOp { op: BinOpcode, left: &'a Expr, right: &'a Expr },
+    UnaOp { op: UnaOpcode, operand: &'a Expr },
+    Funcall { name: &'a str, args: &'a [Expr] },
+    BadParse { expr: &'a Expr },
+}
+
+// As strings, since they may not fit in a JSON number
+#[derive(Serialize)]
+struct RationalRepr {
+    num: String,
+    den: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub enum UnaOpcode {
-    Factorial,
+    Factorial, Neg,
 }
 pub use UnaOpcode::*;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub enum BinOpcode {
-    Add, Sub, Mul, Div, Exp, And, Or, Xor, LShift, RShift
+    Add, Sub, Mul, Div, Exp, And, Or, Xor, LShift, RShift,
+    Mod, IntDiv, Eq, Ne, Lt, Le, Gt, Ge,
 }
 pub use BinOpcode::*;
 
-#[derive(Debug, PartialEq)]
+impl BinOpcode {
+    /// How the operator is usually written
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Add => "+", Sub => "-", Mul => "*", Div => "/", Exp => "^",
+            And => "&", Or => "|", Xor => "xor", LShift => "<<", RShift => ">>",
+            Mod => "%", IntDiv => "//", Eq => "==", Ne => "!=",
+            Lt => "<", Le => "<=", Gt => ">", Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub enum NumSource {
-    Digits, Words
+    Digits, Words, Hex, Octal, Binary,
 }
 pub use NumSource::*;
 
+impl NumSource {
+    /// The base the number was written in. Words are decimal.
+    pub fn radix(self) -> u32 {
+        match self {
+            Digits | Words => 10,
+            Hex => 16,
+            Octal => 8,
+            Binary => 2,
+        }
+    }
+}
+
 pub fn to_num(i: i64) -> Num {
     Num::from_integer(i.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::best_parse;
+    use serde_json::json;
+
+    #[test]
+    fn test_serialize() {
+        let e = best_parse("1.5 * -x").unwrap();
+        assert_eq!(serde_json::to_value(&e).unwrap(), json!({
+            "type": "binOp", "op": "mul", "span": {"start": 0, "end": 8},
+            "left": {"type": "number", "value": {"num": "3", "den": "2"}, "source": "digits",
+                     "span": {"start": 0, "end": 3}},
+            "right": {"type": "unaOp", "op": "neg", "span": {"start": 6, "end": 8},
+                      "operand": {"type": "var", "name": "x", "span": {"start": 7, "end": 8}}},
+        }));
+
+        let e = best_parse("max(2d6, 0x10)").unwrap();
+        let v = serde_json::to_value(&e).unwrap();
+        assert_eq!((&v["type"], &v["name"]), (&json!("funcall"), &json!("max")));
+        assert_eq!(v["args"][0]["count"], 2);
+        assert_eq!(v["args"][1]["source"], "hex");
+    }
+}
@@ -0,0 +1,384 @@
+#[macro_use] extern crate simple_error;
+
+use clap::Parser;
+use counter_parser::eval::EvalLimits;
+use counter_parser::game::{GameRules, Outcome, Sessions};
+use counter_parser::rank::Ranking;
+use counter_parser::rules::RuleSet;
+use counter_parser::store::{JsonStore, MemoryStore, Store};
+use counter_parser::types::Result;
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{self, Interval};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+/// A Discord bot that keeps count in counting channels
+#[derive(Parser, Debug)]
+#[clap(author, version)]
+struct Args {
+    /// The bot's token
+    #[clap(long, env = "DISCORD_TOKEN", hide_env_values = true)]
+    token: String,
+
+    /// A channel to count in, by id. May be given more than once
+    #[clap(long = "channel", required = true)]
+    channels: Vec<String>,
+
+    /// A JSON file to keep counts in between runs
+    #[clap(long)]
+    state: Option<PathBuf>,
+
+    /// Base URL of the Discord REST API
+    #[clap(long, default_value = "https://discord.com/api/v10")]
+    api: String,
+
+    /// A JSON file of rules on how counted numbers may be written
+    #[clap(long)]
+    rules: Option<PathBuf>,
+
+    /// How to choose between partial parses of a message
+    #[clap(long, value_enum, default_value_t = Ranking::Size)]
+    ranking: Ranking,
+
+    #[clap(flatten)]
+    limits: EvalLimits,
+}
+
+// Gateway opcodes
+const DISPATCH: u8 = 0;
+const HEARTBEAT: u8 = 1;
+const IDENTIFY: u8 = 2;
+const RECONNECT: u8 = 7;
+const INVALID_SESSION: u8 = 9;
+const HELLO: u8 = 10;
+
+// GUILD_MESSAGES | MESSAGE_CONTENT
+const INTENTS: u64 = (1 << 9) | (1 << 15);
+
+// Percent-encoded for the reactions route
+const CORRECT: &str = "%E2%9C%85";
+const WRONG: &str = "%E2%9D%8C";
+
+#[derive(Deserialize, Debug)]
+struct Payload {
+    op: u8,
+    #[serde(default)]
+    d: Value,
+    s: Option<u64>,
+    t: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Message {
+    id: String,
+    channel_id: String,
+    content: String,
+    author: Author,
+}
+
+#[derive(Deserialize, Debug)]
+struct Author {
+    id: String,
+    #[serde(default)]
+    bot: bool,
+}
+
+struct Bot {
+    token: String,
+    api: String,
+    http: reqwest::Client,
+    channels: HashSet<String>,
+    sessions: Arc<Sessions>,
+}
+
+impl Bot {
+    fn new(token: &str, api: &str, channels: &[String], rules: GameRules,
+           store: Arc<dyn Store>) -> Result<Bot> {
+        let sessions = Sessions::new(store);
+        for channel in channels {
+            sessions.create(channel, rules.clone())?;
+        }
+
+        Ok(Bot {
+            token: token.to_string(),
+            api: api.trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+            channels: channels.iter().cloned().collect(),
+            sessions: Arc::new(sessions),
+        })
+    }
+
+    async fn gateway_url(&self) -> Result<String> {
+        let res: Value = self.http.get(format!("{}/gateway/bot", self.api))
+            .header("Authorization", format!("Bot {}", self.token))
+            .send().await?
+            .error_for_status()?
+            .json().await?;
+        let url = res["url"].as_str().ok_or_else(|| simple_error!("No gateway URL"))?;
+        Ok(format!("{}/?v=10&encoding=json", url.trim_end_matches('/')))
+    }
+
+    /// Counts messages from one connection to the gateway, until the
+    /// gateway closes it or asks for a reconnect.
+    async fn run(&self, url: &str) -> Result<()> {
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await?;
+        let mut seq: Option<u64> = None;
+        let mut heartbeat: Option<Interval> = None;
+
+        loop {
+            let msg = tokio::select! {
+                msg = ws.next() => msg,
+                _ = tick(&mut heartbeat) => {
+                    ws.send(gateway_msg(HEARTBEAT, json!(seq))).await?;
+                    continue;
+                }
+            };
+
+            let text = match msg {
+                Some(Ok(WsMessage::Text(text))) => text,
+                Some(Ok(WsMessage::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+            };
+
+            // A payload we can't read shouldn't drop the connection
+            let payload: Payload = match serde_json::from_str(&text) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    eprintln!("Skipping unreadable payload: {}", e);
+                    continue;
+                }
+            };
+            if payload.s.is_some() {
+                seq = payload.s;
+            }
+
+            match payload.op {
+                HELLO => {
+                    let ms = payload.d["heartbeat_interval"].as_u64().unwrap_or(41_250);
+                    heartbeat = Some(time::interval(Duration::from_millis(ms)));
+                    ws.send(gateway_msg(IDENTIFY, json!({
+                        "token": self.token,
+                        "intents": INTENTS,
+                        "properties": { "os": std::env::consts::OS,
+                                        "browser": "counter-parser",
+                                        "device": "counter-parser" },
+                    }))).await?;
+                }
+                HEARTBEAT => ws.send(gateway_msg(HEARTBEAT, json!(seq))).await?,
+                DISPATCH if payload.t.as_deref() == Some("MESSAGE_CREATE") => {
+                    let msg: Message = match serde_json::from_value(payload.d) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            eprintln!("Skipping unreadable message: {}", e);
+                            continue;
+                        }
+                    };
+                    // One bad message shouldn't take the bot down
+                    if let Err(e) = self.on_message(&msg).await {
+                        eprintln!("Handling message {} failed: {}", msg.id, e);
+                    }
+                }
+                RECONNECT | INVALID_SESSION => return Ok(()),
+                _ => (),
+            }
+        }
+    }
+
+    async fn on_message(&self, msg: &Message) -> Result<()> {
+        if msg.author.bot || !self.channels.contains(&msg.channel_id) {
+            return Ok(());
+        }
+
+        // Judging may evaluate up to the time limit, so it's kept off the
+        // async workers
+        let (sessions, channel, user, content) =
+            (self.sessions.clone(), msg.channel_id.clone(), msg.author.id.clone(), msg.content.clone());
+        let outcomes = tokio::task::spawn_blocking(
+            move || sessions.submit(&channel, &user, &content).map_err(|e| e.to_string())).await??;
+        if outcomes.iter().any(Outcome::is_correct) {
+            self.react(msg, CORRECT).await
+        } else if outcomes.iter().all(|o| *o == Outcome::NoNumber) {
+            Ok(())
+        } else {
+            self.react(msg, WRONG).await?;
+            let reply: Vec<_> = outcomes.iter().map(|o| o.to_string()).collect();
+            self.reply(msg, &reply.join(". ")).await
+        }
+    }
+
+    async fn react(&self, msg: &Message, emoji: &str) -> Result<()> {
+        self.http.put(format!("{}/channels/{}/messages/{}/reactions/{}/@me",
+                              self.api, msg.channel_id, msg.id, emoji))
+            .header("Authorization", format!("Bot {}", self.token))
+            .header("Content-Length", "0")
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn reply(&self, msg: &Message, content: &str) -> Result<()> {
+        self.http.post(format!("{}/channels/{}/messages", self.api, msg.channel_id))
+            .header("Authorization", format!("Bot {}", self.token))
+            .json(&json!({
+                "content": content,
+                "message_reference": { "message_id": msg.id },
+            }))
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+fn gateway_msg(op: u8, d: Value) -> WsMessage {
+    WsMessage::Text(json!({ "op": op, "d": d }).to_string())
+}
+
+// Waits for the next heartbeat, or forever before the gateway has
+// said how often to beat
+async fn tick(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => { interval.tick().await; }
+        None => std::future::pending().await,
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let store: Arc<dyn Store> = match &args.state {
+        Some(path) => Arc::new(JsonStore::open(path)?),
+        None => Arc::new(MemoryStore::new()),
+    };
+    let expr_rules = match &args.rules {
+        Some(path) => RuleSet::from_file(path)?,
+        None => RuleSet::default(),
+    };
+    let rules = GameRules { expr_rules, ranking: args.ranking, limits: args.limits.clone(), ..Default::default() };
+    let bot = Bot::new(&args.token, &args.api, &args.channels, rules, store)?;
+
+    loop {
+        let res = match bot.gateway_url().await {
+            Ok(url) => bot.run(&url).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            eprintln!("Gateway connection failed: {}", e);
+        }
+        println!("Reconnecting...");
+        time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    type Requests = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    // Records every request, and answers /gateway/bot with `gateway`
+    fn mock_rest(requests: Requests, gateway: String) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = HttpServer::new(move || {
+            let requests = requests.clone();
+            let gateway = gateway.clone();
+            App::new().default_service(web::to(move |req: HttpRequest, body: String| {
+                let requests = requests.clone();
+                let gateway = gateway.clone();
+                async move {
+                    if req.path().ends_with("/gateway/bot") {
+                        return HttpResponse::Ok().json(json!({ "url": gateway }));
+                    }
+                    requests.lock().unwrap().push(
+                        (req.method().to_string(), req.path().to_string(), body));
+                    HttpResponse::NoContent().finish()
+                }
+            }))
+        })
+            .workers(1)
+            .listen(listener).unwrap()
+            .run();
+        actix_web::rt::spawn(server);
+
+        format!("http://{}/api", addr)
+    }
+
+    fn message_create(seq: u64, id: &str, channel: &str, user: &str, content: &str, bot: bool) -> WsMessage {
+        WsMessage::Text(json!({
+            "op": DISPATCH, "s": seq, "t": "MESSAGE_CREATE",
+            "d": { "id": id, "channel_id": channel, "content": content,
+                   "author": { "id": user, "bot": bot } },
+        }).to_string())
+    }
+
+    #[actix_web::test]
+    async fn test_bot() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway_addr = listener.local_addr().unwrap();
+
+        let gateway = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            ws.send(gateway_msg(HELLO, json!({ "heartbeat_interval": 60_000 }))).await.unwrap();
+            let mut identified = false;
+            while let Some(Ok(WsMessage::Text(text))) = ws.next().await {
+                let p: Payload = serde_json::from_str(&text).unwrap();
+                if p.op == IDENTIFY {
+                    assert_eq!(p.d["token"], "secret");
+                    identified = true;
+                    break;
+                }
+            }
+
+            for msg in [message_create(1, "m1", "count", "a", "1", false),
+                        WsMessage::Text("not json".into()),
+                        WsMessage::Text(json!({ "op": DISPATCH, "s": 2, "t": "MESSAGE_CREATE",
+                                                "d": { "id": "m0" } }).to_string()),
+                        message_create(2, "m2", "count", "b", "one plus one", false),
+                        message_create(3, "m3", "chat", "a", "5", false),
+                        message_create(4, "m4", "count", "bot", "3", true),
+                        message_create(5, "m5", "count", "b", "3", false),
+                        message_create(6, "m6", "count", "a", "lol", false)] {
+                ws.send(msg).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+            identified
+        });
+
+        let requests: Requests = Default::default();
+        let api = mock_rest(requests.clone(), format!("ws://{}", gateway_addr));
+        let bot = Bot::new("secret", &api, &["count".to_string()], Default::default(),
+                           Arc::new(MemoryStore::new())).unwrap();
+
+        let url = bot.gateway_url().await.unwrap();
+        assert!(url.starts_with(&format!("ws://{}", gateway_addr)));
+        bot.run(&url).await.unwrap();
+        assert!(gateway.await.unwrap());
+
+        let requests = requests.lock().unwrap();
+        let paths: Vec<_> = requests.iter().map(|(m, p, _)| format!("{} {}", m, p)).collect();
+        assert_eq!(paths, vec![
+            format!("PUT /api/channels/count/messages/m1/reactions/{}/@me", CORRECT),
+            format!("PUT /api/channels/count/messages/m2/reactions/{}/@me", CORRECT),
+            format!("PUT /api/channels/count/messages/m5/reactions/{}/@me", WRONG),
+            "POST /api/channels/count/messages".to_string(),
+        ]);
+
+        let reply: Value = serde_json::from_str(&requests[3].2).unwrap();
+        assert_eq!(reply["message_reference"]["message_id"], "m5");
+        assert_eq!(reply["content"], "b counted twice in a row. The count was reset after reaching 2");
+    }
+}
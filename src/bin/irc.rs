@@ -0,0 +1,275 @@
+use clap::Parser;
+use counter_parser::eval::EvalLimits;
+use counter_parser::game::{GameRules, Outcome, Sessions};
+use counter_parser::rank::Ranking;
+use counter_parser::rules::RuleSet;
+use counter_parser::store::{JsonStore, MemoryStore, Store};
+use counter_parser::types::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// An IRC bot that keeps count in counting channels
+#[derive(Parser, Debug)]
+#[clap(author, version)]
+struct Args {
+    /// The server to connect to, as host:port
+    #[clap(long, default_value = "irc.libera.chat:6667")]
+    server: String,
+
+    #[clap(long, default_value = "counterbot")]
+    nick: String,
+
+    /// A channel to count in. May be given more than once
+    #[clap(long = "channel", required = true)]
+    channels: Vec<String>,
+
+    /// A JSON file to keep counts in between runs
+    #[clap(long)]
+    state: Option<PathBuf>,
+
+    /// A JSON file of rules on how counted numbers may be written
+    #[clap(long)]
+    rules: Option<PathBuf>,
+
+    /// How to choose between partial parses of a message
+    #[clap(long, value_enum, default_value_t = Ranking::Size)]
+    ranking: Ranking,
+
+    #[clap(flatten)]
+    limits: EvalLimits,
+}
+
+/// One line from the server, split into its prefix, command and
+/// parameters. A trailing parameter (after " :") may contain spaces.
+#[derive(Debug, PartialEq)]
+struct IrcLine<'a> {
+    prefix: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+impl<'a> IrcLine<'a> {
+    fn parse(line: &'a str) -> Option<IrcLine<'a>> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (prefix, rest) = match line.strip_prefix(':') {
+            Some(rest) => {
+                let (prefix, rest) = rest.split_once(' ')?;
+                (Some(prefix), rest)
+            }
+            None => (None, line),
+        };
+
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut words = middle.split(' ').filter(|w| !w.is_empty());
+        let command = words.next()?;
+        let mut params: Vec<_> = words.collect();
+        params.extend(trailing);
+
+        Some(IrcLine { prefix, command, params })
+    }
+
+    /// The nick of whoever sent the line
+    fn nick(&self) -> Option<&'a str> {
+        self.prefix.map(|p| p.split('!').next().unwrap_or(p))
+    }
+}
+
+struct Bot {
+    nick: String,
+    channels: Vec<String>,
+    sessions: Arc<Sessions>,
+}
+
+impl Bot {
+    fn new(nick: &str, channels: &[String], rules: GameRules, store: Arc<dyn Store>) -> Result<Bot> {
+        // Channel names are case-insensitive
+        let channels: Vec<_> = channels.iter().map(|c| c.to_lowercase()).collect();
+        let sessions = Sessions::new(store);
+        for channel in channels.iter() {
+            sessions.create(channel, rules.clone())?;
+        }
+
+        Ok(Bot { nick: nick.to_string(), channels, sessions: Arc::new(sessions) })
+    }
+
+    /// Registers, joins the channels and counts in them until the
+    /// server closes the connection.
+    async fn run<S>(&self, stream: S) -> Result<()>
+        where S: AsyncRead + AsyncWrite
+    {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+        let mut nick = self.nick.clone();
+
+        send(&mut writer, &format!("NICK {}", nick)).await?;
+        send(&mut writer, &format!("USER {} 0 * :counter-parser", nick)).await?;
+
+        while {
+            buf.clear();
+            reader.read_until(b'\n', &mut buf).await? > 0
+        } {
+            // IRC doesn't promise UTF-8, and plenty of clients send
+            // Latin-1, so read what we can rather than give up
+            let line = String::from_utf8_lossy(&buf);
+            let line = match IrcLine::parse(&line) {
+                Some(line) => line,
+                None => continue,
+            };
+
+            match (line.command, line.params.as_slice()) {
+                ("PING", params) => {
+                    send(&mut writer, &format!("PONG :{}", params.first().unwrap_or(&""))).await?;
+                }
+                // Welcome, so registration is done
+                ("001", _) => send(&mut writer, &format!("JOIN {}", self.channels.join(","))).await?,
+                // Nick in use
+                ("433", _) => {
+                    nick.push('_');
+                    send(&mut writer, &format!("NICK {}", nick)).await?;
+                }
+                ("PRIVMSG", [target, text]) => {
+                    let channel = target.to_lowercase();
+                    if let (true, Some(from)) = (self.channels.contains(&channel), line.nick()) {
+                        if let Some(reply) = self.on_message(&channel, from, text).await? {
+                            send(&mut writer, &format!("PRIVMSG {} :{}", target, reply)).await?;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    // What to say about `text`, if anything
+    async fn on_message(&self, channel: &str, from: &str, text: &str) -> Result<Option<String>> {
+        // Judging may evaluate up to the time limit, so it's kept off the
+        // async workers
+        let (sessions, channel, from, text) =
+            (self.sessions.clone(), channel.to_string(), from.to_string(), text.to_string());
+        let outcomes = tokio::task::spawn_blocking(
+            move || sessions.submit(&channel, &from, &text).map_err(|e| e.to_string())).await??;
+        let said: Vec<_> = outcomes.iter()
+            .filter(|o| **o != Outcome::NoNumber)
+            .map(|o| o.to_string())
+            .collect();
+
+        Ok(if said.is_empty() { None } else { Some(said.join(". ")) })
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, line: &str) -> Result<()> {
+    writer.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let store: Arc<dyn Store> = match &args.state {
+        Some(path) => Arc::new(JsonStore::open(path)?),
+        None => Arc::new(MemoryStore::new()),
+    };
+    let expr_rules = match &args.rules {
+        Some(path) => RuleSet::from_file(path)?,
+        None => RuleSet::default(),
+    };
+    let rules = GameRules { expr_rules, ranking: args.ranking, limits: args.limits.clone(), ..Default::default() };
+    let bot = Bot::new(&args.nick, &args.channels, rules, store)?;
+
+    loop {
+        let res = match TcpStream::connect(&args.server).await {
+            Ok(stream) => bot.run(stream).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            eprintln!("Connection to {} failed: {}", args.server, e);
+        }
+        println!("Reconnecting...");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufRead, Lines};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(IrcLine::parse(":a!b@c PRIVMSG #count :one plus one\r\n"),
+                   Some(IrcLine { prefix: Some("a!b@c"), command: "PRIVMSG",
+                                  params: vec!["#count", "one plus one"] }));
+        assert_eq!(IrcLine::parse("PING :irc.example.net"),
+                   Some(IrcLine { prefix: None, command: "PING", params: vec!["irc.example.net"] }));
+        assert_eq!(IrcLine::parse(":srv 001 bot :Welcome").unwrap().params, vec!["bot", "Welcome"]);
+        assert_eq!(IrcLine::parse(":a!b@c PRIVMSG #x :hi").unwrap().nick(), Some("a"));
+        assert_eq!(IrcLine::parse(""), None);
+    }
+
+    async fn next<R: AsyncBufRead + Unpin>(lines: &mut Lines<R>) -> String {
+        lines.next_line().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bot() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A fake server, which hands back everything the bot sent
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = tokio::io::split(stream);
+            let mut lines = BufReader::new(reader).lines();
+            let mut got = Vec::new();
+            got.push(next(&mut lines).await);
+            got.push(next(&mut lines).await);
+            send(&mut writer, ":srv 433 * counterbot :Nickname is already in use").await.unwrap();
+            got.push(next(&mut lines).await);
+            send(&mut writer, ":srv 001 counterbot_ :Welcome").await.unwrap();
+            got.push(next(&mut lines).await);
+            send(&mut writer, "PING :srv").await.unwrap();
+            got.push(next(&mut lines).await);
+
+            // Not UTF-8, and no number either
+            writer.write_all(b":c!c@h PRIVMSG #count :caf\xe9\r\n").await.unwrap();
+            for line in [":a!a@h PRIVMSG #Count :1",
+                         ":b!b@h PRIVMSG #count :hmm",
+                         ":b!b@h PRIVMSG #other :2",
+                         ":b!b@h PRIVMSG #count :one plus one",
+                         ":b!b@h PRIVMSG #count :3"] {
+                send(&mut writer, line).await.unwrap();
+            }
+            got.push(next(&mut lines).await);
+            got.push(next(&mut lines).await);
+            got.push(next(&mut lines).await);
+            got
+        });
+
+        let bot = Bot::new("counterbot", &["#count".to_string()], Default::default(),
+                           Arc::new(MemoryStore::new())).unwrap();
+        bot.run(TcpStream::connect(addr).await.unwrap()).await.unwrap();
+
+        assert_eq!(server.await.unwrap(), vec![
+            "NICK counterbot",
+            "USER counterbot 0 * :counter-parser",
+            "NICK counterbot_",
+            "JOIN #count",
+            "PONG :srv",
+            "PRIVMSG #Count :a counted 1",
+            "PRIVMSG #count :b counted 2",
+            "PRIVMSG #count :b counted twice in a row. The count was reset after reaching 2",
+        ]);
+    }
+}
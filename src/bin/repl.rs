@@ -1,14 +1,9 @@
-#[macro_use] extern crate simple_error;
+use counter_parser::eval::EvalLimits;
+use counter_parser::protocol::{self, Evaluator, Protocol};
+use counter_parser::rank::Ranking;
+use counter_parser::types::Result;
 
-use counter_parser::eval;
-use counter_parser::parse;
-use counter_parser::types;
-
-use std::io::{self, BufRead, Read, Write};
-use std::str;
-use std::default::Default;
 use clap::Parser;
-use types::{Result, Request, Response};
 
 /// A normal number parser
 #[derive(Parser, Debug)]
@@ -17,99 +12,54 @@ struct Args {
     /// Whether to speak JSON
     #[clap(long)]
     json: bool,
-}
-
-fn main() -> Result<()> {
-    let args = Args::parse();
 
-    respond(io::stdin(), io::stdout(), args.json)?;
+    /// How to choose between partial parses of a line
+    #[clap(long, value_enum, default_value_t = Ranking::Size)]
+    ranking: Ranking,
 
-    Ok(())
+    #[clap(flatten)]
+    limits: EvalLimits,
 }
 
-fn respond<R, W>(reader: R, writer: W, json: bool) -> Result<()>
-    where R: Read,
-          W: Write
-{
-    if json {
-        return respond_json(reader, writer);
-    } else {
-        return respond_lines(reader, writer);
-    }
-}
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let protocol = if args.json { Protocol::Json } else { Protocol::Lines };
+    let evaluator = Evaluator { ranking: args.ranking, limits: args.limits };
 
-fn respond_lines<R, W>(reader: R, mut writer: W) -> Result<()>
-    where R: Read,
-          W: Write,
-{
-    let mut breader = io::BufReader::new(reader);
-    let mut linebuf = Vec::new();
-
-    println!("Welcome to mathematica");
-
-    while {
-        linebuf.clear();
-        let n = breader.read_until(b'\n', &mut linebuf)?;
-        n > 0
-    } {
-        let line = str::from_utf8(&linebuf)?;
-        let msg = match eval_line(&line) {
-            Ok(res) => res,
-            Err(e) => format!("{}\n", e),
-        };
-        writer.write_all(msg.as_bytes())?;
-        writer.flush()?;
+    if protocol == Protocol::Lines {
+        println!("Welcome to mathematica");
     }
-
+    protocol::respond(protocol, tokio::io::stdin(), tokio::io::stdout(), &evaluator,
+                      std::future::pending()).await?;
     println!("Session closed");
-    Ok(())
-}
 
-fn respond_json<R, W>(reader: R, mut writer: W) -> Result<()>
-    where R: Read,
-          W: Write,
-{
-    let mut breader = io::BufReader::new(reader);
-
-    println!("Reading JSON...");
-
-    while let Ok(req) = serde_json::from_reader::<_, Request>(&mut breader)
-        .map_err(|e| { println!("JSON read failed: {:?}", e); e })
-    {
-        println!("Got JSON!");
-        let res = match eval_line(&req.message) {
-            Ok(str) => Response::Good { val: Some(str) },
-            Err(e) => Response::Bad { message: format!("{}", e) }
-        };
-        println!("Responding with {:?}", res);
-        serde_json::to_writer(&mut writer, &res)?;
-    }
-
-    println!("Session closed");
     Ok(())
 }
 
-
-fn eval_line(line: &str) -> Result<String> {
-    let expr = parse::best_parse(line).ok_or_else(
-        || simple_error!("No good parses in '{}'", line))?;
-
-    eval::eval(&expr, &Default::default()).map(|v| format!("{}\n", v.to_string()))
-}
-
 #[cfg(test)]
 mod tests {
     use counter_parser::ast::*;
     use counter_parser::grammar;
+    use counter_parser::parse;
+    use counter_parser::rank;
     use counter_parser::util;
-    use super::*;
     #[test]
     fn test_terms() {
-        let cases = &[("(42)", Box::new(Number(to_num(42), Digits))),
+        let cases = &[("(42)", expr(Number(to_num(42), Digits))),
                       ("one hundred fifty + 3",
-                       Box::new(BinOp(Add,
-                                      Box::new(Number(to_num(150), Words)),
-                                      Box::new(Number(to_num(3), Digits)))))
+                       expr(BinOp(Add,
+                                  expr(Number(to_num(150), Words)),
+                                  expr(Number(to_num(3), Digits))))),
+                      ("sqrt(144)",
+                       expr(Funcall("sqrt".to_string(),
+                                    vec![expr(Number(to_num(144), Digits))]))),
+                      ("max(1, two) + 3",
+                       expr(BinOp(Add,
+                                  expr(Funcall("max".to_string(),
+                                               vec![expr(Number(to_num(1), Digits)),
+                                                    expr(Number(to_num(2), Words))])),
+                                  expr(Number(to_num(3), Digits))))),
         ];
 
         let parser = grammar::TermParser::new();
@@ -118,7 +68,8 @@ mod tests {
             let lexer = util::TokenLexer::new(string);
             let parse = parser.parse(string, lexer);
             assert!(parse.is_ok(), "Failed to parse {:?}: {:?}", string, parse.err());
-            assert_eq!(parse.unwrap(), *num, "String parsed to the wrong expr: {:?}", string);
+            let parse = parse.unwrap();
+            assert!(parse.same_shape(num), "String parsed to the wrong expr: {:?}: {:?}", string, parse);
         }
     }
 
@@ -137,7 +88,9 @@ mod tests {
               ("twenty thousand", 20000),
               ("twenty thousand five hundred fifteen", 20515),
               ("one hundred twenty three thousand five hundred fifteen", 123515),
-              ("one million two thousand three", 1_002_003)
+              ("one million two thousand three", 1_002_003),
+              ("negative twelve", -12),
+              ("negative one hundred five", -105),
             ];
 
         let parser = grammar::NumWordsParser::new();
@@ -175,44 +128,120 @@ mod tests {
     fn test_precedence() {
         let cases: &[(&'static str, Expr)] = &[
             ("1 - 2 - 3",
-             Box::new(BinOp(Sub,
-                            Box::new(BinOp(Sub,
-                                           Box::new(Number(to_num(1), Digits)),
-                                           Box::new(Number(to_num(2), Digits)))),
-                            Box::new(Number(to_num(3), Digits)),
+             expr(BinOp(Sub,
+                        expr(BinOp(Sub,
+                                   expr(Number(to_num(1), Digits)),
+                                   expr(Number(to_num(2), Digits)))),
+                        expr(Number(to_num(3), Digits)),
              ))),
             ("1 + 2 * 3",
-             Box::new(BinOp(Add,
-                            Box::new(Number(to_num(1), Digits)),
-                            Box::new(BinOp(Mul,
-                                           Box::new(Number(to_num(2), Digits)),
-                                           Box::new(Number(to_num(3), Digits))))))),
+             expr(BinOp(Add,
+                        expr(Number(to_num(1), Digits)),
+                        expr(BinOp(Mul,
+                                   expr(Number(to_num(2), Digits)),
+                                   expr(Number(to_num(3), Digits))))))),
             ("1 * 2 + 3",
-             Box::new(BinOp(Add,
-                            Box::new(BinOp(Mul,
-                                           Box::new(Number(to_num(1), Digits)),
-                                           Box::new(Number(to_num(2), Digits)))),
-                            Box::new(Number(to_num(3), Digits))))),
+             expr(BinOp(Add,
+                        expr(BinOp(Mul,
+                                   expr(Number(to_num(1), Digits)),
+                                   expr(Number(to_num(2), Digits)))),
+                        expr(Number(to_num(3), Digits))))),
             ("1 * 2 ^ 3",
-             Box::new(BinOp(Mul,
-                            Box::new(Number(to_num(1), Digits)),
-                            Box::new(BinOp(Exp,
-                                           Box::new(Number(to_num(2), Digits)),
-                                           Box::new(Number(to_num(3), Digits))))))),
+             expr(BinOp(Mul,
+                        expr(Number(to_num(1), Digits)),
+                        expr(BinOp(Exp,
+                                   expr(Number(to_num(2), Digits)),
+                                   expr(Number(to_num(3), Digits))))))),
             ("1 ^ 2 ^ 3",
-             Box::new(BinOp(Exp,
-                            Box::new(Number(to_num(1), Digits)),
-                            Box::new(BinOp(Exp,
-                                           Box::new(Number(to_num(2), Digits)),
-                                           Box::new(Number(to_num(3), Digits))))))),
+             expr(BinOp(Exp,
+                        expr(Number(to_num(1), Digits)),
+                        expr(BinOp(Exp,
+                                   expr(Number(to_num(2), Digits)),
+                                   expr(Number(to_num(3), Digits))))))),
+            ("5 -3",
+             expr(BinOp(Sub,
+                        expr(Number(to_num(5), Digits)),
+                        expr(Number(to_num(3), Digits))))),
+            ("-2 ^ 2",
+             expr(UnaOp(Neg,
+                        expr(BinOp(Exp,
+                                   expr(Number(to_num(2), Digits)),
+                                   expr(Number(to_num(2), Digits))))))),
+            ("2 * -3",
+             expr(BinOp(Mul,
+                        expr(Number(to_num(2), Digits)),
+                        expr(UnaOp(Neg, expr(Number(to_num(3), Digits))))))),
+            ("-(3 + 4)",
+             expr(UnaOp(Neg,
+                        expr(BinOp(Add,
+                                   expr(Number(to_num(3), Digits)),
+                                   expr(Number(to_num(4), Digits))))))),
+            ("- five",
+             expr(UnaOp(Neg, expr(Number(to_num(5), Words))))),
+            ("negative twelve",
+             expr(Number(to_num(-12), Words))),
+            ("100 % 7 + 96",
+             expr(BinOp(Add,
+                        expr(BinOp(Mod,
+                                   expr(Number(to_num(100), Digits)),
+                                   expr(Number(to_num(7), Digits)))),
+                        expr(Number(to_num(96), Digits))))),
+            ("17 // 2 equals 4 * 2",
+             expr(BinOp(Eq,
+                        expr(BinOp(IntDiv,
+                                   expr(Number(to_num(17), Digits)),
+                                   expr(Number(to_num(2), Digits)))),
+                        expr(BinOp(Mul,
+                                   expr(Number(to_num(4), Digits)),
+                                   expr(Number(to_num(2), Digits))))))),
+            ("ten divided by 2",
+             expr(BinOp(Div,
+                        expr(Number(to_num(10), Words)),
+                        expr(Number(to_num(2), Digits))))),
+            ("3 3 ==",
+             expr(BinOp(Eq,
+                        expr(Number(to_num(3), Digits)),
+                        expr(Number(to_num(3), Digits))))),
+            ("mod 9 4",
+             expr(BinOp(Mod,
+                        expr(Number(to_num(9), Digits)),
+                        expr(Number(to_num(4), Digits))))),
+            ("2^-1",
+             expr(BinOp(Exp,
+                        expr(Number(to_num(2), Digits)),
+                        expr(UnaOp(Neg, expr(Number(to_num(1), Digits))))))),
+            ("1 << -1",
+             expr(BinOp(LShift,
+                        expr(Number(to_num(1), Digits)),
+                        expr(UnaOp(Neg, expr(Number(to_num(1), Digits))))))),
+            ("6 & -2",
+             expr(BinOp(And,
+                        expr(Number(to_num(6), Digits)),
+                        expr(UnaOp(Neg, expr(Number(to_num(2), Digits))))))),
+            ("3 -2 *",
+             expr(BinOp(Mul,
+                        expr(Number(to_num(3), Digits)),
+                        expr(UnaOp(Neg, expr(Number(to_num(2), Digits))))))),
+            ("* -2 3",
+             expr(BinOp(Mul,
+                        expr(UnaOp(Neg, expr(Number(to_num(2), Digits)))),
+                        expr(Number(to_num(3), Digits))))),
+            ("- 5 3",
+             expr(BinOp(Sub,
+                        expr(Number(to_num(5), Digits)),
+                        expr(Number(to_num(3), Digits))))),
+            ("- 5 * 3",
+             expr(BinOp(Mul,
+                        expr(UnaOp(Neg, expr(Number(to_num(5), Digits)))),
+                        expr(Number(to_num(3), Digits))))),
         ];
 
-        let parser = grammar::TopLevelParser::new();
-
+        // Through best_candidate_with, which retries "-2" as a signed
+        // number, but still only taking parses of the whole line
         for (string, res) in cases.iter() {
-            let lexer = util::TokenLexer::new(string);
-            let parse = parser.parse(string, lexer);
-            assert_eq!(parse.unwrap(), *res, "Wrong parse");
+            let parse = parse::best_candidate_with(string, &rank::BySize).unwrap();
+            assert!(parse.is_whole_line(), "Partial parse of {:?}: {:?}", string, parse);
+            assert!(parse.expr.same_shape(res), "Wrong parse of {:?}: {:?}", string, parse.expr);
         }
     }
 }
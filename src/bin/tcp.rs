@@ -0,0 +1,145 @@
+use clap::Parser;
+use counter_parser::eval::EvalLimits;
+use counter_parser::protocol::{self, Evaluator, Protocol};
+use counter_parser::rank::Ranking;
+use counter_parser::types::Result;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+
+/// A number parser over TCP
+#[derive(Parser, Debug)]
+#[clap(author, version)]
+struct Args {
+    #[clap(long, default_value = "127.0.0.1:2369")]
+    bind: String,
+
+    /// The protocol clients speak. Left out, it's guessed for each
+    /// connection: JSON if the client's first byte opens an object
+    #[clap(long, value_enum)]
+    protocol: Option<Protocol>,
+
+    /// How to choose between partial parses of a line
+    #[clap(long, value_enum, default_value_t = Ranking::Size)]
+    ranking: Ranking,
+
+    #[clap(flatten)]
+    limits: EvalLimits,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let evaluator = Evaluator { ranking: args.ranking, limits: args.limits };
+
+    let listener = TcpListener::bind(&args.bind).await?;
+    println!("Listening on {}", listener.local_addr()?);
+    serve(listener, args.protocol, Arc::new(evaluator), async {
+        let _ = tokio::signal::ctrl_c().await;
+        println!("Shutting down...");
+    }).await
+}
+
+/// Runs a session for each client until `shutdown` resolves, then
+/// stops accepting, ends every session, and waits for them to finish.
+async fn serve<F>(listener: TcpListener, protocol: Option<Protocol>, evaluator: Arc<Evaluator>,
+                  shutdown: F) -> Result<()>
+    where F: Future<Output = ()>
+{
+    let (stop, stopping) = watch::channel(());
+    // Each session holds a sender, so the channel closes once they
+    // have all finished
+    let (running, mut finished) = mpsc::channel::<()>(1);
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = &mut shutdown => break,
+        };
+
+        let evaluator = evaluator.clone();
+        let mut stopping = stopping.clone();
+        let running = running.clone();
+        tokio::spawn(async move {
+            let stopped = async move { let _ = stopping.changed().await; };
+            if let Err(e) = session(stream, protocol, &evaluator, stopped).await {
+                eprintln!("Session with {} failed: {}", addr, e);
+            }
+            drop(running);
+        });
+    }
+
+    stop.send(())?;
+    drop(running);
+    let _ = finished.recv().await;
+    Ok(())
+}
+
+async fn session<F>(mut stream: TcpStream, protocol: Option<Protocol>, evaluator: &Evaluator,
+                    shutdown: F) -> Result<()>
+    where F: Future<Output = ()>
+{
+    let (reader, writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    tokio::pin!(shutdown);
+
+    let protocol = match protocol {
+        Some(protocol) => protocol,
+        // A client that hasn't said anything yet mustn't hold up shutdown
+        None => tokio::select! {
+            start = reader.fill_buf() => match start?.iter().find(|b| !b.is_ascii_whitespace()) {
+                Some(b'{') => Protocol::Json,
+                _ => Protocol::Lines,
+            },
+            _ = &mut shutdown => return Ok(()),
+        },
+    };
+
+    protocol::respond(protocol, reader, writer, evaluator, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, Lines};
+    use tokio::sync::oneshot;
+
+    async fn next(lines: &mut Lines<BufReader<TcpStream>>) -> String {
+        lines.next_line().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = serve(listener, None, Default::default(), async { let _ = stopped.await; });
+
+        let clients = async {
+            // Both clients stay connected at once
+            let mut lines = BufReader::new(TcpStream::connect(addr).await.unwrap()).lines();
+            let mut json = BufReader::new(TcpStream::connect(addr).await.unwrap()).lines();
+
+            lines.get_mut().write_all(b"1 + 1\n").await.unwrap();
+            json.get_mut().write_all(br#"{"message": "two times three"}"#).await.unwrap();
+            assert_eq!(next(&mut lines).await, "2");
+            assert_eq!(next(&mut json).await, r#"{"type":"good","val":"6\n"}"#);
+
+            lines.get_mut().write_all(b"d1 * 5\n").await.unwrap();
+            assert_eq!(next(&mut lines).await, "5");
+
+            // Shutting down closes the connections still open
+            stop.send(()).unwrap();
+            let mut rest = Vec::new();
+            lines.into_inner().into_inner().read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(json.next_line().await.unwrap(), None);
+        };
+
+        let (res, ()) = tokio::join!(server, clients);
+        res.unwrap();
+    }
+}
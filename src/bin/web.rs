@@ -1,61 +1,978 @@
 #[macro_use] extern crate simple_error;
 extern crate derive_more;
 
-use actix_web::http::header::ContentType;
-use actix_web::{self, web, post, HttpServer, App, Responder, HttpResponse};
-use serde::{Deserialize, Serialize};
-use serde_json::{Value};
+use actix_web::{self, web, get, post, HttpServer, App, HttpRequest, HttpResponse, ResponseError};
+use actix_web::http::StatusCode;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use clap::parser::ValueSource;
 use counter_parser::parse;
-use counter_parser::eval;
-use counter_parser::types::{Request, Response};
+use counter_parser::eval::{self, Env, EvalError, EvalLimits};
+use counter_parser::ast::Num;
+use counter_parser::funcs;
+use counter_parser::metrics::{Metrics, RequestOutcome};
+use counter_parser::game::{GameRules, SessionError, Sessions};
+use counter_parser::rank::Ranking;
+use counter_parser::stats::{Board, Stats};
+use counter_parser::store::MemoryStore;
+use counter_parser::types::{CreateSessionRequest, GameResponse, ParseResponse, RecordRequest,
+                            Request, Response, SubmitRequest};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::result;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use derive_more::Display;
+use futures_util::StreamExt;
+use rayon::prelude::*;
 
 type Result<A, E = Box<dyn std::error::Error>> = result::Result<A, E>;
 
+/// The counter-parser HTTP server. Options given here, or through
+/// their environment variables, override the config file.
+#[derive(Parser, Debug)]
+#[clap(author, version)]
+struct Args {
+    /// A TOML file of settings, named as the long options are but with
+    /// underscores, and with evaluation limits under [limits]
+    #[clap(long, env = "COUNTER_PARSER_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, as host:port [default: 127.0.0.1:2369]
+    #[clap(long, env = "COUNTER_PARSER_BIND")]
+    bind: Option<String>,
+
+    /// Listen on this Unix socket instead of a TCP address
+    #[clap(long, env = "COUNTER_PARSER_UNIX")]
+    unix: Option<PathBuf>,
+
+    /// Worker threads [default: one per core]
+    #[clap(long, env = "COUNTER_PARSER_WORKERS")]
+    workers: Option<usize>,
+
+    /// Largest request body accepted, in bytes [default: 262144]
+    #[clap(long, env = "COUNTER_PARSER_MAX_BODY")]
+    max_body: Option<usize>,
+
+    /// Most requests one call to /eval/batch may hold [default: 1000]
+    #[clap(long, env = "COUNTER_PARSER_MAX_BATCH")]
+    max_batch: Option<usize>,
+
+    /// How to choose between partial parses of a message [default: size]
+    #[clap(long, value_enum, env = "COUNTER_PARSER_RANKING")]
+    ranking: Option<Ranking>,
+
+    #[clap(flatten)]
+    limits: EvalLimits,
+}
+
+/// The server's settings, as read from a config file.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    bind: String,
+    unix: Option<PathBuf>,
+    workers: Option<usize>,
+    max_body: usize,
+    max_batch: usize,
+    ranking: Ranking,
+    limits: EvalLimits,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: "127.0.0.1:2369".to_string(),
+            unix: None,
+            workers: None,
+            max_body: 262_144,
+            max_batch: 1_000,
+            ranking: Ranking::Size,
+            limits: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    /// The settings `matches` (parsed as `Args`) ask for: the config
+    /// file's, if there is one, with any options given laid over them.
+    fn load(matches: &ArgMatches) -> Result<Config> {
+        let args = Args::from_arg_matches(matches)?;
+        let mut config: Config = match &args.config {
+            Some(path) => toml::from_str(&std::fs::read_to_string(path)
+                .map_err(|e| simple_error!("Can't read {}: {}", path.display(), e))?)?,
+            None => Default::default(),
+        };
+
+        config.bind = args.bind.unwrap_or(config.bind);
+        config.unix = args.unix.or(config.unix);
+        config.workers = args.workers.or(config.workers);
+        config.max_body = args.max_body.unwrap_or(config.max_body);
+        config.max_batch = args.max_batch.unwrap_or(config.max_batch);
+        config.ranking = args.ranking.unwrap_or(config.ranking);
+
+        // Limits always have values, so only take those given
+        // explicitly over the file's
+        let given = |id| !matches!(matches.value_source(id), None | Some(ValueSource::DefaultValue));
+        let (limits, file) = (args.limits, &mut config.limits);
+        if given("max-bits") { file.max_bits = limits.max_bits; }
+        if given("max-exponent") { file.max_exponent = limits.max_exponent; }
+        if given("max-factorial") { file.max_factorial = limits.max_factorial; }
+        if given("max-dice") { file.max_dice = limits.max_dice; }
+        if given("max-sequence-arg") { file.max_sequence_arg = limits.max_sequence_arg; }
+        if given("max-prime-bits") { file.max_prime_bits = limits.max_prime_bits; }
+        if given("max-nodes") { file.max_nodes = limits.max_nodes; }
+        if given("timeout-ms") { file.timeout_ms = limits.timeout_ms; }
+
+        Ok(config)
+    }
+}
+
+/// How many `set` variables a websocket with `?state=true` may hold,
+/// besides `ans`.
+const MAX_SOCKET_VARS: usize = 64;
+
+/// The `max_batch` setting, wrapped to give it its own `app_data` slot.
+#[derive(Debug, Clone, Copy)]
+struct MaxBatch(usize);
+
+/// Why a request to `/eval` and friends failed, answered as a
+/// `Response::Bad`.
 #[derive(Debug, Display)]
 enum UserError {
-    #[display(fmt = "no parses found")]
+    #[display(fmt = "Malformed request: {}", _0)]
+    BadJson(String),
+    #[display(fmt = "No parses found")]
     NoParse,
-    #[display(fmt = "evaluation failed")]
-    BadEval,
-    #[display(fmt = "internal server error")]
-    Internal
+    #[display(fmt = "{}", _0)]
+    BadEval(String),
+    #[display(fmt = "{}", _0)]
+    Limit(String),
+    #[display(fmt = "Batches may hold at most {} requests", _0)]
+    BatchTooLarge(usize),
+    #[display(fmt = "Sockets may hold at most {} variables", _0)]
+    TooManyVariables(usize),
+    #[display(fmt = "{}", _0)]
+    BadRules(String),
+    #[display(fmt = "No stats for {}", _0)]
+    NoStats(String),
+    #[display(fmt = "No session {}", _0)]
+    NoSession(String),
+    #[display(fmt = "Session {} already exists", _0)]
+    SessionExists(String),
+    #[display(fmt = "Internal server error")]
+    Internal,
+}
+
+impl UserError {
+    fn code(&self) -> &'static str {
+        match self {
+            UserError::BadJson(_) => "bad_json",
+            UserError::NoParse => "no_parse",
+            UserError::BadEval(_) => "eval_failed",
+            UserError::Limit(_) => "limit_exceeded",
+            UserError::BatchTooLarge(_) => "batch_too_large",
+            UserError::TooManyVariables(_) => "too_many_variables",
+            UserError::BadRules(_) => "bad_rules",
+            UserError::NoStats(_) => "no_stats",
+            UserError::NoSession(_) => "no_session",
+            UserError::SessionExists(_) => "session_exists",
+            UserError::Internal => "internal",
+        }
+    }
+
+    fn from_eval(e: Box<dyn std::error::Error>) -> UserError {
+        match e.downcast_ref::<EvalError>() {
+            Some(EvalError::TooLarge(_)) | Some(EvalError::TimedOut) => UserError::Limit(e.to_string()),
+            _ => UserError::BadEval(e.to_string()),
+        }
+    }
+
+    // Errors from the session routes that aren't already a UserError
+    // are the sessions' or, failing that, the store's
+    fn from_session(e: Box<dyn std::error::Error>) -> UserError {
+        let e = match e.downcast::<UserError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        match e.downcast_ref::<SessionError>() {
+            Some(SessionError::NoSession(id)) => UserError::NoSession(id.clone()),
+            Some(SessionError::Exists(id)) => UserError::SessionExists(id.clone()),
+            None => {
+                eprintln!("Session store failed: {}", e);
+                UserError::Internal
+            }
+        }
+    }
 }
 
-// impl actix_web::error::ResponseError for UserError {
-//     fn error_response(&self) -> HttpResposne {
-//         HttpResponse::build(self.status_code())
-//             .
+impl std::error::Error for UserError {}
+
+impl ResponseError for UserError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UserError::BadJson(_) | UserError::BadRules(_) => StatusCode::BAD_REQUEST,
+            UserError::NoStats(_) | UserError::NoSession(_) => StatusCode::NOT_FOUND,
+            UserError::SessionExists(_) => StatusCode::CONFLICT,
+            UserError::NoParse | UserError::BadEval(_) | UserError::Limit(_)
+                | UserError::TooManyVariables(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            UserError::BatchTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            UserError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .body(serde_json::to_string(&Response::from(self)).unwrap_or_default())
+    }
+}
+
+impl From<&UserError> for Response {
+    fn from(e: &UserError) -> Response {
+        Response::Bad { message: e.to_string(), code: Some(e.code().to_string()) }
+    }
+}
+
+impl From<&UserError> for GameResponse {
+    fn from(e: &UserError) -> GameResponse {
+        GameResponse::Bad { message: e.to_string(), code: Some(e.code().to_string()) }
+    }
+}
 
 #[actix_web::main]
-async fn main() -> std::result::Result<(), std::io::Error> {
+async fn main() -> Result<()> {
+    let config = Config::load(&Args::command().get_matches())?;
+    let limits = web::Data::new(config.limits.clone());
+    let ranking = web::Data::new(config.ranking);
+    let metrics = web::Data::new(Metrics::new());
+    let max_batch = web::Data::new(MaxBatch(config.max_batch));
+    let max_body = config.max_body;
+    let stats = web::Data::new(Mutex::new(Stats::new()));
+    let defaults = GameRules { ranking: config.ranking, limits: config.limits, ..Default::default() };
+    let sessions = web::Data::new(Sessions::with_defaults(Arc::new(MemoryStore::new()), defaults));
+
     println!("Starting counter-parser server...");
-    HttpServer::new(|| {
+    let server = HttpServer::new(move || {
         App::new()
+            .app_data(web::PayloadConfig::new(max_body))
+            .app_data(limits.clone())
+            .app_data(ranking.clone())
+            .app_data(max_batch.clone())
+            .app_data(stats.clone())
+            .app_data(sessions.clone())
+            .app_data(metrics.clone())
+            .service(health_svc)
+            .service(metrics_svc)
             .service(eval_svc)
-    })
-        .bind(("127.0.0.1", 2369))?
-        .run()
-        .await
+            .service(eval_batch_svc)
+            .service(parse_svc)
+            .service(ws_svc)
+            .service(record_svc)
+            .service(user_stats_svc)
+            .service(leaderboard_svc)
+            .configure(session_routes)
+    });
+    let server = match config.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let server = match &config.unix {
+        Some(path) => server.bind_uds(path)?,
+        None => server.bind(&config.bind)?,
+    };
+
+    Ok(server.run().await?)
+}
+
+
+#[get("/healthz")]
+async fn health_svc() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/plain").body("ok\n")
 }
 
+#[get("/metrics")]
+async fn metrics_svc(metrics: web::Data<Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
 
 #[post("/eval")]
-async fn eval_svc(body: String) -> Result<HttpResponse> {
-    let req: Request = serde_json::from_str(&body)?;
+async fn eval_svc(body: String,
+                  limits: web::Data<EvalLimits>,
+                  ranking: web::Data<Ranking>,
+                  metrics: web::Data<Metrics>) -> Result<HttpResponse, UserError> {
+    let req: Request = serde_json::from_str(&body).map_err(|e| UserError::BadJson(e.to_string()))?;
+    // Evaluating may take up to the timeout, so keep it off the worker
+    let ranking = **ranking;
+    let val = web::block(move || eval_request(&req, &Env::new(), &limits, ranking, &metrics))
+        .await.map_err(|_| UserError::Internal)??;
+
+    Ok(
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::to_string(&Response::Good { val: Some(val) })
+                  .map_err(|_| UserError::Internal)?)
+    )
+}
 
-    let expr = parse::best_parse(&req.message).ok_or_else(
-        || simple_error!("No parses found"))?;
+fn eval_request(req: &Request, env: &Env, limits: &EvalLimits, ranking: Ranking,
+                metrics: &Metrics) -> Result<String, UserError> {
+    eval_in(req, env, limits, ranking, metrics).map(|val| format!("{}", val))
+}
+
+fn eval_in(req: &Request, env: &Env, limits: &EvalLimits, ranking: Ranking,
+           metrics: &Metrics) -> Result<Num, UserError> {
+    let start = Instant::now();
+    let ranker = ranking.ranker_with(&funcs::BUILTINS, limits);
+    let (best, candidates) = parse::best_candidate_counted(&req.message, &*ranker);
+    metrics.record_parse(start.elapsed(), candidates, best.as_ref().map(|c| &c.expr.node));
+
+    let expr = match best {
+        Some(best) => best.expr,
+        None => {
+            metrics.record_outcome(RequestOutcome::NoParse);
+            return Err(UserError::NoParse);
+        }
+    };
 
-    let val = eval::eval(&expr, &Default::default())?;
+    let start = Instant::now();
+    let res = eval::eval_with(&expr, env, &funcs::BUILTINS, limits);
+    metrics.record_eval(start.elapsed());
+    metrics.record_outcome(if res.is_ok() { RequestOutcome::Good } else { RequestOutcome::Bad });
+
+    res.map_err(UserError::from_eval)
+}
+
+/// Evaluates an array of requests, answering each in place. Failures
+/// are per request, so one bad message doesn't fail the batch.
+#[post("/eval/batch")]
+async fn eval_batch_svc(body: String,
+                        limits: web::Data<EvalLimits>,
+                        ranking: web::Data<Ranking>,
+                        max_batch: web::Data<MaxBatch>,
+                        metrics: web::Data<Metrics>) -> Result<HttpResponse, UserError> {
+    let reqs: Vec<Request> = serde_json::from_str(&body)
+        .map_err(|e| UserError::BadJson(e.to_string()))?;
+    if reqs.len() > max_batch.0 {
+        return Err(UserError::BatchTooLarge(max_batch.0));
+    }
+
+    let ranking = **ranking;
+    let responses = web::block(move || {
+        reqs.par_iter()
+            .map(|req| match eval_request(req, &Env::new(), &limits, ranking, &metrics) {
+                Ok(val) => Response::Good { val: Some(val) },
+                Err(e) => Response::from(&e),
+            })
+            .collect::<Vec<_>>()
+    }).await.map_err(|_| UserError::Internal)?;
 
     Ok(
-        HttpResponse::Ok().body(
-            serde_json::to_string(
-                &Response::Good { val: Some(format!("{}", val)) })?)
-            //.set(ContentType::JSON)
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::to_string(&responses).map_err(|_| UserError::Internal)?)
     )
 }
 
+#[post("/parse")]
+async fn parse_svc(body: String, limits: web::Data<EvalLimits>,
+                   ranking: web::Data<Ranking>) -> Result<HttpResponse, UserError> {
+    let req: Request = serde_json::from_str(&body).map_err(|e| UserError::BadJson(e.to_string()))?;
+
+    let ranker = ranking.ranker_with(&funcs::BUILTINS, &limits);
+    let candidates = parse::all_parses_with(&req.message, &*ranker);
+    let best = parse::best_of(&candidates);
+    let res = ParseResponse {
+        best,
+        span: best.map(|c| c.expr.span.clone()),
+        candidates: &candidates,
+    };
+
+    Ok(
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::to_string(&res).map_err(|_| UserError::Internal)?)
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct WsOptions {
+    /// Whether the socket keeps variables between messages
+    #[serde(default)]
+    state: bool,
+}
+
+/// Answers each `Request` text frame with a `Response` frame. With
+/// `?state=true`, each result is also bound to `ans`, and to the
+/// request's `set` variable if it has one, until the socket closes.
+#[get("/ws")]
+async fn ws_svc(req: HttpRequest, body: web::Payload, options: web::Query<WsOptions>,
+                limits: web::Data<EvalLimits>,
+                ranking: web::Data<Ranking>,
+                metrics: web::Data<Metrics>) -> actix_web::Result<HttpResponse> {
+    let (response, mut session, mut stream) = actix_ws::handle(&req, body)?;
+    let ranking = **ranking;
+    let keep_state = options.state;
+
+    actix_web::rt::spawn(async move {
+        let mut env = Env::new();
+
+        while let Some(Ok(msg)) = stream.next().await {
+            let text = match msg {
+                actix_ws::Message::Text(text) => text,
+                actix_ws::Message::Ping(bytes) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                actix_ws::Message::Close(reason) => {
+                    let _ = session.close(reason).await;
+                    return;
+                }
+                _ => continue,
+            };
+
+            let req = match serde_json::from_str::<Request>(&text) {
+                Ok(req) => req,
+                Err(e) => {
+                    let frame = serde_json::to_string(&Response::from(&UserError::BadJson(e.to_string())))
+                        .unwrap_or_default();
+                    if session.text(frame).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            // `ans` is always there, so it doesn't count against the cap
+            let vars = env.len() - env.contains_key("ans") as usize;
+            let new_var = matches!(&req.set, Some(name) if name != "ans" && !env.contains_key(name));
+            if keep_state && new_var && vars >= MAX_SOCKET_VARS {
+                let frame = serde_json::to_string(&Response::from(&UserError::TooManyVariables(MAX_SOCKET_VARS)))
+                    .unwrap_or_default();
+                if session.text(frame).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+
+            // Like /eval, evaluate off the worker. The environment goes
+            // along and comes back with the result
+            let (limits, metrics) = (limits.clone(), metrics.clone());
+            let evaluated = web::block(move || {
+                let res = eval_in(&req, &env, &limits, ranking, &metrics);
+                (req, env, res)
+            }).await;
+            let (req, res) = match evaluated {
+                Ok((req, evaluated_env, res)) => {
+                    env = evaluated_env;
+                    (req, res)
+                }
+                Err(_) => break,
+            };
+
+            let res = match res {
+                Ok(val) => {
+                    let res = Response::Good { val: Some(format!("{}", val)) };
+                    if keep_state {
+                        if let Some(name) = req.set {
+                            env.insert(name, val.clone());
+                        }
+                        env.insert("ans".to_string(), val);
+                    }
+                    res
+                }
+                Err(e) => Response::from(&e),
+            };
+
+            let frame = serde_json::to_string(&res).unwrap_or_default();
+            if session.text(frame).await.is_err() {
+                return;
+            }
+        }
+
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+#[post("/stats")]
+async fn record_svc(body: String, stats: web::Data<Mutex<Stats>>,
+                    limits: web::Data<EvalLimits>,
+                    ranking: web::Data<Ranking>) -> Result<HttpResponse, UserError> {
+    let req: RecordRequest = serde_json::from_str(&body).map_err(|e| UserError::BadJson(e.to_string()))?;
+
+    // Ranking may evaluate the candidates, so keep it off the worker
+    web::block(move || {
+        let ranker = ranking.ranker_with(&funcs::BUILTINS, &limits);
+        stats.lock().unwrap().record_with(&req.user, &req.message, req.accepted, &*ranker);
+    }).await.map_err(|_| UserError::Internal)?;
+
+    json_response(&Response::Good { val: None })
+}
+
+#[get("/stats/{user}")]
+async fn user_stats_svc(user: web::Path<String>,
+                        stats: web::Data<Mutex<Stats>>) -> Result<HttpResponse, UserError> {
+    let stats = stats.lock().unwrap();
+    let s = stats.get(&user).ok_or_else(|| UserError::NoStats(user.into_inner()))?;
+    json_response(s)
+}
+
+#[get("/leaderboard/{board}")]
+async fn leaderboard_svc(board: web::Path<Board>,
+                         stats: web::Data<Mutex<Stats>>) -> Result<HttpResponse, UserError> {
+    let entries = stats.lock().unwrap().leaderboard(*board);
+    json_response(&entries)
+}
 
+fn json_response<T: Serialize>(body: &T) -> Result<HttpResponse, UserError> {
+    Ok(
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::to_string(body).map_err(|_| UserError::Internal)?)
+    )
+}
+
+fn session_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_session_svc)
+        .service(count_svc)
+        .service(submit_svc)
+        .service(reset_svc)
+        .service(high_score_svc)
+        .service(history_svc)
+        .service(session_leaderboard_svc);
+}
+
+/// Answers with what `f` returns, or the error it fails with. `f` runs
+/// on the blocking pool, since it may wait on a session that's busy
+/// evaluating and saving.
+async fn game_response<T, F>(f: F) -> Result<HttpResponse>
+    where T: Serialize,
+          F: FnOnce() -> Result<T> + Send + 'static
+{
+    let (status, body) = web::block(move || {
+        let (status, body) = match f() {
+            Ok(body) => (StatusCode::OK, serde_json::to_string(&body)),
+            Err(e) => {
+                let e = UserError::from_session(e);
+                (e.status_code(), serde_json::to_string(&GameResponse::from(&e)))
+            }
+        };
+        body.map(|body| (status, body))
+    }).await??;
+    Ok(HttpResponse::build(status).content_type("application/json").body(body))
+}
+
+#[post("/sessions")]
+async fn create_session_svc(body: String, sessions: web::Data<Sessions>) -> Result<HttpResponse> {
+    game_response(move || create_session(&body, &sessions)).await
+}
+
+fn create_session(body: &str, sessions: &Sessions) -> Result<GameResponse> {
+    let req: CreateSessionRequest = serde_json::from_str(body)
+        .map_err(|e| UserError::BadJson(e.to_string()))?;
+    let rules = req.rules(sessions.defaults()).map_err(|e| UserError::BadRules(e.to_string()))?;
+    sessions.create(&req.id, rules)?;
+    Ok(GameResponse::Created { id: req.id })
+}
+
+#[get("/sessions/{id}")]
+async fn count_svc(id: web::Path<String>, sessions: web::Data<Sessions>) -> Result<HttpResponse> {
+    game_response(move || sessions.with(&id, |s| GameResponse::Count {
+        count: s.count().to_string(),
+        expected: s.expected().to_string(),
+        last_user: s.last_user().map(String::from),
+        run: s.state().run,
+    })).await
+}
+
+#[post("/sessions/{id}/messages")]
+async fn submit_svc(id: web::Path<String>, body: String,
+                    sessions: web::Data<Sessions>) -> Result<HttpResponse> {
+    game_response(move || submit(&id, &body, &sessions)).await
+}
+
+fn submit(id: &str, body: &str, sessions: &Sessions) -> Result<GameResponse> {
+    let req: SubmitRequest = serde_json::from_str(body)
+        .map_err(|e| UserError::BadJson(e.to_string()))?;
+    let outcomes = sessions.submit(id, &req.user, &req.message)?;
+    Ok(GameResponse::Outcomes { outcomes })
+}
+
+#[post("/sessions/{id}/reset")]
+async fn reset_svc(id: web::Path<String>, sessions: web::Data<Sessions>) -> Result<HttpResponse> {
+    game_response(move || sessions.update(&id, |s| GameResponse::Outcomes { outcomes: vec![s.reset()] })).await
+}
+
+#[get("/sessions/{id}/highscore")]
+async fn high_score_svc(id: web::Path<String>, sessions: web::Data<Sessions>) -> Result<HttpResponse> {
+    game_response(move || sessions.with(&id, |s| GameResponse::HighScore {
+        high_score: s.high_score().to_string(),
+        best_run: s.state().best_run,
+    })).await
+}
+
+#[get("/sessions/{id}/history")]
+async fn history_svc(id: web::Path<String>, sessions: web::Data<Sessions>) -> Result<HttpResponse> {
+    game_response(move || sessions.with(&id, |s| GameResponse::History {
+        events: s.history().cloned().collect(),
+    })).await
+}
+
+#[get("/sessions/{id}/leaderboard/{board}")]
+async fn session_leaderboard_svc(path: web::Path<(String, Board)>,
+                                 sessions: web::Data<Sessions>) -> Result<HttpResponse> {
+    let (id, board) = path.into_inner();
+    game_response(move || sessions.with(&id, |s| s.stats().leaderboard(board))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use counter_parser::game::GameState;
+    use counter_parser::stats::Entry;
+    use counter_parser::store::Store;
+
+    #[actix_web::test]
+    async fn test_config() {
+        let load = |args: &[&str]| {
+            Config::load(&Args::command().try_get_matches_from(args).unwrap()).unwrap()
+        };
+        assert_eq!(load(&["web"]), Config::default());
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, br#"
+            bind = "0.0.0.0:80"
+            workers = 2
+            ranking = "span"
+
+            [limits]
+            max_bits = 128
+            max_dice = 10
+        "#).unwrap();
+        let path = file.path().to_str().unwrap();
+
+        let config = load(&["web", "--config", path, "--workers", "4", "--max-dice", "20"]);
+        assert_eq!((config.bind.as_str(), config.workers, config.ranking), ("0.0.0.0:80", Some(4), Ranking::Span));
+        assert_eq!((config.limits.max_bits, config.limits.max_dice, config.limits.max_nodes), (128, 20, 1_000));
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"colour = \"blue\"\n").unwrap();
+        let args = ["web", "--config", file.path().to_str().unwrap()];
+        assert!(Config::load(&Args::command().get_matches_from(args)).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_eval_errors() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(EvalLimits::default()))
+                .app_data(web::Data::new(Ranking::Size))
+                .app_data(web::Data::new(Metrics::new()))
+                .service(eval_svc)).await;
+
+        for (body, status, code) in [(r#"{"message": "2 * 3"}"#, 200, None),
+                                     (r#"{"message": 3"#, 400, Some("bad_json")),
+                                     (r#"{"message": "hello"}"#, 422, Some("eval_failed")),
+                                     (r#"{"message": "1 / 0"}"#, 422, Some("eval_failed")),
+                                     (r#"{"message": "9 ^ 9 ^ 9 ^ 9"}"#, 422, Some("limit_exceeded")),
+                                     (r#"{"message": "?"}"#, 422, Some("no_parse"))] {
+            let req = test::TestRequest::post().uri("/eval").set_payload(body).to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status, "{}", body);
+            assert_eq!(res.headers().get("content-type").unwrap(), "application/json");
+
+            let res: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(res["code"].as_str(), code, "{}", body);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_eval_batch() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(EvalLimits::default()))
+                .app_data(web::Data::new(Ranking::Size))
+                .app_data(web::Data::new(MaxBatch(3)))
+                .app_data(web::Data::new(Metrics::new()))
+                .service(eval_batch_svc)).await;
+
+        let post = |body: &str| {
+            test::TestRequest::post().uri("/eval/batch").set_payload(body.to_string()).to_request()
+        };
+
+        let body: serde_json::Value = test::call_and_read_body_json(
+            &app, post(r#"[{"message": "1 + 1"}, {"message": "1 / 0"}, {"message": "three"}]"#)).await;
+        assert_eq!(body, serde_json::json!([
+            {"type": "good", "val": "2"},
+            {"type": "bad", "message": "Division by zero", "code": "eval_failed"},
+            {"type": "good", "val": "3"},
+        ]));
+
+        let res = test::call_service(&app, post(&format!("[{}]", [r#"{"message": "1"}"#; 4].join(",")))).await;
+        assert_eq!(res.status(), 413);
+        let res = test::call_service(&app, post(r#"{"message": "1"}"#)).await;
+        assert_eq!(res.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_ws() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = HttpServer::new(|| {
+            App::new()
+                .app_data(web::Data::new(EvalLimits::default()))
+                .app_data(web::Data::new(Ranking::Size))
+                .app_data(web::Data::new(Metrics::new()))
+                .service(ws_svc)
+        })
+            .workers(1)
+            .listen(listener).unwrap()
+            .run();
+        actix_web::rt::spawn(server);
+
+        type Ws = tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+        async fn ask(ws: &mut Ws, req: &str) -> serde_json::Value {
+            ws.send(Message::Text(req.to_string())).await.unwrap();
+            match ws.next().await {
+                Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap(),
+                other => panic!("Expected a text frame, got {:?}", other),
+            }
+        }
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws?state=true", addr))
+            .await.unwrap();
+        assert_eq!(ask(&mut ws, r#"{"message": "6 * 7", "set": "x"}"#).await["val"], "42");
+        assert_eq!(ask(&mut ws, r#"{"message": "ans + 1"}"#).await["val"], "43");
+        assert_eq!(ask(&mut ws, r#"{"message": "x / 2"}"#).await["val"], "21");
+        assert_eq!(ask(&mut ws, "nope").await["code"], "bad_json");
+
+        // Past the cap, new names are refused but old ones still update
+        for i in 1..MAX_SOCKET_VARS {
+            let req = format!(r#"{{"message": "{}", "set": "v{}"}}"#, i, i);
+            assert_eq!(ask(&mut ws, &req).await["val"], i.to_string());
+        }
+        let res = ask(&mut ws, r#"{"message": "1", "set": "y"}"#).await;
+        assert_eq!(res["code"], "too_many_variables");
+        assert_eq!(ask(&mut ws, r#"{"message": "x + 1", "set": "x"}"#).await["val"], "43");
+        assert_eq!(ask(&mut ws, r#"{"message": "5", "set": "ans"}"#).await["val"], "5");
+        ws.close(None).await.unwrap();
+
+        // Without state, each message stands alone
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+            .await.unwrap();
+        assert_eq!(ask(&mut ws, r#"{"message": "1 + 1", "set": "x"}"#).await["val"], "2");
+        assert_eq!(ask(&mut ws, r#"{"message": "ans"}"#).await["code"], "eval_failed");
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_routes() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(EvalLimits::default()))
+                .app_data(web::Data::new(Ranking::Size))
+                .app_data(web::Data::new(Metrics::new()))
+                .service(health_svc)
+                .service(metrics_svc)
+                .service(eval_svc)).await;
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, "ok\n");
+
+        for message in ["1 + 1", "so 2 - 1 right", "1 / 0", "?"] {
+            let req = test::TestRequest::post().uri("/eval")
+                .set_payload(serde_json::json!({ "message": message }).to_string()).to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get("content-type").unwrap(), "text/plain; version=0.0.4");
+        let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+        for line in ["counter_parser_requests_total{outcome=\"good\"} 2",
+                     "counter_parser_requests_total{outcome=\"bad\"} 1",
+                     "counter_parser_requests_total{outcome=\"no_parse\"} 1",
+                     "counter_parser_eval_seconds_count 3",
+                     "counter_parser_parse_candidates_count 4",
+                     "counter_parser_binops_total{op=\"-\"} 1"] {
+            assert!(body.lines().any(|l| l == line), "Missing {:?} in:\n{}", line, body);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_parse_route() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(EvalLimits::default()))
+                .app_data(web::Data::new(Ranking::Size))
+                .service(parse_svc)).await;
+
+        let req = test::TestRequest::post().uri("/parse")
+            .set_payload(r#"{"message": "I think 2 + 2 is it"}"#).to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["span"], serde_json::json!({"start": 8, "end": 13}));
+        assert_eq!(body["best"]["expr"]["type"], "binOp");
+        assert_eq!(body["best"]["expr"]["left"]["value"], serde_json::json!({"num": "2", "den": "1"}));
+        assert!(body["candidates"].as_array().unwrap().len() > 1);
+
+        let req = test::TestRequest::post().uri("/parse").set_payload("{").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_stats_routes() {
+        let stats = web::Data::new(Mutex::new(Stats::new()));
+        let app = test::init_service(
+            App::new()
+                .app_data(stats.clone())
+                .app_data(web::Data::new(EvalLimits::default()))
+                .app_data(web::Data::new(Ranking::Size))
+                .service(record_svc)
+                .service(user_stats_svc)
+                .service(leaderboard_svc)).await;
+
+        for (user, message, accepted) in [("a", "1", true), ("b", "1 + 1", true), ("a", "4", false)] {
+            let req = test::TestRequest::post().uri("/stats")
+                .set_payload(serde_json::to_string(&RecordRequest {
+                    user: user.into(), message: message.into(), accepted,
+                }).unwrap())
+                .to_request();
+            assert!(test::call_service(&app, req).await.status().is_success());
+        }
+
+        let req = test::TestRequest::get().uri("/leaderboard/complexity").to_request();
+        let entries: Vec<Entry> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(entries[0], Entry { user: "b".into(), score: 3 });
+
+        let req = test::TestRequest::get().uri("/stats/a").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["failures"], 1);
+
+        let req = test::TestRequest::get().uri("/stats/nobody").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.headers().get("content-type").unwrap(), "application/json");
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "no_stats");
+
+        let req = test::TestRequest::post().uri("/stats").set_payload("{").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "bad_json");
+
+        let req = test::TestRequest::get().uri("/leaderboard/correct").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get("content-type").unwrap(), "application/json");
+    }
+
+    #[actix_web::test]
+    async fn test_session_routes() {
+        let defaults = GameRules { limits: EvalLimits { max_bits: 8, ..Default::default() }, ..Default::default() };
+        let sessions = web::Data::new(Sessions::with_defaults(Arc::new(MemoryStore::new()), defaults));
+        let app = test::init_service(
+            App::new()
+                .app_data(sessions.clone())
+                .configure(session_routes)).await;
+
+        let post = |uri: &str, body: &str| {
+            test::TestRequest::post().uri(uri).set_payload(body.to_string()).to_request()
+        };
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+        let res = test::call_service(&app, post("/sessions", r#"{"id": "c", "mode": {"type": "step", "start": 2, "step": 2}}"#)).await;
+        assert_eq!(res.status(), 200);
+        let res = test::call_service(&app, post("/sessions", r#"{"id": "c"}"#)).await;
+        assert_eq!(res.status(), 409);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "session_exists");
+        let res = test::call_service(&app, post("/sessions/c/messages", "{")).await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "bad_json");
+
+        let body: serde_json::Value = test::call_and_read_body_json(
+            &app, post("/sessions/c/messages", r#"{"user": "a", "message": "1 + 1"}"#)).await;
+        assert_eq!(body["type"], "outcomes");
+        assert_eq!(body["outcomes"][0], serde_json::json!({"type": "correct", "user": "a", "count": "2"}));
+
+        let body: serde_json::Value = test::call_and_read_body_json(
+            &app, post("/sessions/c/messages", r#"{"user": "b", "message": "five"}"#)).await;
+        assert_eq!(body["outcomes"][0]["type"], "wrongNumber");
+        assert_eq!(body["outcomes"][1]["type"], "countReset");
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get("/sessions/c")).await;
+        assert_eq!(body, serde_json::json!({"type": "count", "count": "0", "expected": "2",
+                                            "lastUser": null, "run": 0}));
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get("/sessions/c/highscore")).await;
+        assert_eq!((&body["highScore"], &body["bestRun"]), (&serde_json::json!("2"), &serde_json::json!(1)));
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get("/sessions/c/history")).await;
+        assert_eq!(body["events"].as_array().unwrap().len(), 2);
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, post("/sessions/c/reset", "")).await;
+        assert_eq!(body["outcomes"][0]["type"], "countReset");
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get("/sessions/c/leaderboard/correct")).await;
+        assert_eq!(body[0]["user"], "a");
+
+        let res = test::call_service(&app, get("/sessions/nope")).await;
+        assert_eq!(res.status(), 404);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "no_session");
+        let res = test::call_service(&app, post("/sessions", r#"{"id": "d", "mode": {"type": "base", "radix": 7}}"#)).await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "bad_rules");
+
+        let res = test::call_service(&app, post("/sessions", r#"{"id": "e", "rules": {"allowedSources": ["words"]}}"#)).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = test::call_and_read_body_json(
+            &app, post("/sessions/e/messages", r#"{"user": "a", "message": "1"}"#)).await;
+        assert_eq!(body["outcomes"][0]["reason"], "Numbers can't be written in decimal");
+
+        // Sessions evaluate under the server's limits
+        let res = test::call_service(&app, post("/sessions", r#"{"id": "f"}"#)).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = test::call_and_read_body_json(
+            &app, post("/sessions/f/messages", r#"{"user": "a", "message": "2 ^ 10 - 1023"}"#)).await;
+        assert_ne!(body["outcomes"][0]["type"], "correct");
+        let body: serde_json::Value = test::call_and_read_body_json(
+            &app, post("/sessions/f/messages", r#"{"user": "a", "message": "2 ^ 3 - 7"}"#)).await;
+        assert_eq!(body["outcomes"][0]["type"], "correct");
+    }
+
+    #[actix_web::test]
+    async fn test_session_store_failure() {
+        struct Broken;
+        impl Store for Broken {
+            fn load(&self, _: &str) -> Result<Option<GameState>> { Ok(None) }
+            fn save(&self, _: &str, _: &GameState) -> Result<()> { bail!("disk full") }
+        }
+        let sessions = web::Data::new(Sessions::new(Arc::new(Broken)));
+        sessions.create("c", Default::default()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(sessions.clone())
+                .configure(session_routes)).await;
+
+        // The store's fault, not the client's
+        let req = test::TestRequest::post().uri("/sessions/c/messages")
+            .set_payload(r#"{"user": "a", "message": "1"}"#).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), 500);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["code"], "internal");
+    }
+}
@@ -1,55 +1,227 @@
 use crate::ast::{self, *};
+use crate::funcs::{self, FunctionRegistry};
+use crate::parse;
 use crate::types::Result;
 use std::collections::HashMap;
 use rand;
 use num::traits::Zero;
 use num::pow::Pow;
 use num::{BigRational, BigInt, ToPrimitive};
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
 
-type Env = HashMap<String, ast::Num>;
+pub type Env = HashMap<String, ast::Num>;
+
+/// Evaluation failures that callers may want to tell apart, rather
+/// than just report.
+#[derive(Debug, Display, PartialEq)]
+pub enum EvalError {
+    #[display(fmt = "Division by zero")]
+    DivisionByZero,
+    #[display(fmt = "Dice need at least one side")]
+    NoSides,
+    #[display(fmt = "Too large: {}", _0)]
+    TooLarge(String),
+    #[display(fmt = "Evaluation took too long")]
+    TimedOut,
+}
+
+impl std::error::Error for EvalError {}
+
+/// Bounds on the work a single evaluation may do. Exceeding any of
+/// them fails with `EvalError::TooLarge` or `EvalError::TimedOut`.
+#[derive(clap::Args, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct EvalLimits {
+    /// Largest numerator or denominator of any intermediate result, in bits
+    #[clap(long, env = "COUNTER_PARSER_MAX_BITS",
+           default_value_t = EvalLimits::default().max_bits)]
+    pub max_bits: u64,
+    /// Largest exponent allowed in a ^ b
+    #[clap(long, env = "COUNTER_PARSER_MAX_EXPONENT",
+           default_value_t = EvalLimits::default().max_exponent)]
+    pub max_exponent: u64,
+    /// Largest argument allowed to !
+    #[clap(long, env = "COUNTER_PARSER_MAX_FACTORIAL",
+           default_value_t = EvalLimits::default().max_factorial)]
+    pub max_factorial: u64,
+    /// Most dice a single roll may throw
+    #[clap(long, env = "COUNTER_PARSER_MAX_DICE",
+           default_value_t = EvalLimits::default().max_dice)]
+    pub max_dice: i64,
+    /// Largest argument allowed to fib and choose, which do work linear
+    /// in it that the timeout can't interrupt
+    #[clap(long, env = "COUNTER_PARSER_MAX_SEQUENCE_ARG",
+           default_value_t = EvalLimits::default().max_sequence_arg)]
+    pub max_sequence_arg: u64,
+    /// Largest argument allowed to isprime, in bits, since a primality
+    /// test can't be interrupted by the timeout either
+    #[clap(long, env = "COUNTER_PARSER_MAX_PRIME_BITS",
+           default_value_t = EvalLimits::default().max_prime_bits)]
+    pub max_prime_bits: u64,
+    /// Largest expression to evaluate, by expr_size
+    #[clap(long, env = "COUNTER_PARSER_MAX_NODES",
+           default_value_t = EvalLimits::default().max_nodes)]
+    pub max_nodes: usize,
+    /// Wall-clock budget for one evaluation, in milliseconds
+    #[clap(long, env = "COUNTER_PARSER_TIMEOUT_MS",
+           default_value_t = EvalLimits::default().timeout_ms)]
+    pub timeout_ms: u64,
+}
+
+// Also the flags' defaults
+impl Default for EvalLimits {
+    fn default() -> EvalLimits {
+        EvalLimits {
+            max_bits: 65_536,
+            max_exponent: 100_000,
+            max_factorial: 5_000,
+            max_dice: 1_000,
+            max_sequence_arg: 100_000,
+            max_prime_bits: 1_024,
+            max_nodes: 1_000,
+            timeout_ms: 1_000,
+        }
+    }
+}
 
 pub fn eval(expr: &Node, env: &Env) -> Result<ast::Num> {
-    match expr {
-        Number(i, _) => Ok(i.clone()),
-        Roll(n, sides) => Ok(roll(*n, *sides)),
-        Var(name) => Ok(env.get(name).map(|v| v.clone()).ok_or_else(
-            || Box::new(simple_error!("Unbound variable {:?}", name)))?),
-        UnaOp(Factorial, a) => Ok(factorial(eval(&a, env)?)?),
-        BinOp(op, l, r) => {
-            let a = eval(&l, env)?;
-            let b = eval(&r, env)?;
-
-            let res = match op {
-                Add => a + b,
-                Sub => a - b,
-                Mul => a * b,
-                Div => a / b,
-                Exp => exp(a, b)?,
-                And => bitand(a, b)?,
-                Or  => bitor(a, b)?,
-                Xor => bitxor(a, b)?,
-                LShift => bitshift(a, b)?,
-                RShift => bitshift(a, -b)?,
-            };
-            Ok(res)
+    eval_with(expr, env, &funcs::BUILTINS, &Default::default())
+}
+
+/// Like `eval`, but calls functions from `funcs` rather than the
+/// built-ins, and enforces `limits` rather than the defaults.
+pub fn eval_with(expr: &Node, env: &Env, funcs: &FunctionRegistry, limits: &EvalLimits)
+                 -> Result<ast::Num>
+{
+    let size = parse::expr_size(expr);
+    if size as usize > limits.max_nodes {
+        bail!(EvalError::TooLarge(format!("expression has {} nodes", size)));
+    }
+
+    let evaluator = Evaluator {
+        env,
+        funcs,
+        limits,
+        deadline: Instant::now() + Duration::from_millis(limits.timeout_ms),
+    };
+    evaluator.eval(expr)
+}
+
+struct Evaluator<'a> {
+    env: &'a Env,
+    funcs: &'a FunctionRegistry,
+    limits: &'a EvalLimits,
+    deadline: Instant,
+}
+
+impl Evaluator<'_> {
+    fn eval(&self, expr: &Node) -> Result<ast::Num> {
+        if Instant::now() > self.deadline {
+            bail!(EvalError::TimedOut);
+        }
+
+        let res = self.eval_node(expr)?;
+
+        let bits = std::cmp::max(res.numer().bits(), res.denom().bits());
+        if bits > self.limits.max_bits {
+            bail!(EvalError::TooLarge(format!("result has {} bits", bits)));
+        }
+        Ok(res)
+    }
+
+    fn eval_node(&self, expr: &Node) -> Result<ast::Num> {
+        let limits = self.limits;
+        match expr {
+            Number(i, _) => Ok(i.clone()),
+            Roll(n, sides) => roll(*n, *sides, limits),
+            Var(name) => Ok(self.env.get(name).cloned().ok_or_else(
+                || Box::new(simple_error!("Unbound variable {:?}", name)))?),
+            UnaOp(Factorial, a) => Ok(factorial(self.eval(a)?, limits)?),
+            UnaOp(Neg, a) => Ok(-self.eval(a)?),
+            BinOp(op, l, r) => {
+                let a = self.eval(l)?;
+                let b = self.eval(r)?;
+
+                let res = match op {
+                    Add => a + b,
+                    Sub => a - b,
+                    Mul => a * b,
+                    Div => div(a, b)?,
+                    Exp => exp(a, b, limits)?,
+                    And => bitand(a, b)?,
+                    Or  => bitor(a, b)?,
+                    Xor => bitxor(a, b)?,
+                    LShift => bitshift(a, b, limits)?,
+                    RShift => bitshift(a, -b, limits)?,
+                    Mod => modulo(&a, &b)?,
+                    IntDiv => intdiv(&a, &b)?,
+                    Eq => truth(a == b),
+                    Ne => truth(a != b),
+                    Lt => truth(a < b),
+                    Le => truth(a <= b),
+                    Gt => truth(a > b),
+                    Ge => truth(a >= b),
+                };
+                Ok(res)
+            }
+            Funcall(f, args) => {
+                let vals = args.iter()
+                    .map(|a| self.eval(a))
+                    .collect::<Result<Vec<_>>>()?;
+
+                self.funcs.call_with(f, &vals, self.limits)
+            }
+            BadParse(e) => Err(simple_error!(
+                "Bad parse encountered in execution! near {:?}", e))?,
         }
-        Funcall(f, _args) => Err(simple_error!("Unknown function '{}'", f))?,
-        BadParse(e) => Err(simple_error!(
-            "Bad parse encountered in execution! near {:?}", e))?,
     }
 }
 
-fn roll(n: i64, sides: i64) -> ast::Num {
-    let mut res = Zero::zero();
+fn truth(b: bool) -> ast::Num {
+    to_num(if b { 1 } else { 0 })
+}
+
+// Floored modulo, so the result takes the sign of the divisor
+pub(crate) fn modulo(a: &ast::Num, b: &ast::Num) -> Result<ast::Num> {
+    Ok(a - b * intdiv(a, b)?)
+}
+
+fn div(a: ast::Num, b: ast::Num) -> Result<ast::Num> {
+    if b.is_zero() {
+        bail!(EvalError::DivisionByZero);
+    }
+    Ok(a / b)
+}
+
+fn intdiv(a: &ast::Num, b: &ast::Num) -> Result<ast::Num> {
+    if b.is_zero() {
+        bail!(EvalError::DivisionByZero);
+    }
+    Ok((a / b).floor())
+}
+
+fn roll(n: i64, sides: i64, limits: &EvalLimits) -> Result<ast::Num> {
+    if sides < 1 {
+        bail!(EvalError::NoSides);
+    }
+    if n > limits.max_dice {
+        bail!(EvalError::TooLarge(format!("{} dice", n)));
+    }
+    let mut res: ast::Num = Zero::zero();
     for _ in 0..n {
         res += BigRational::from_integer(
             (rand::random::<u64>() % (sides as u64) + 1).into());
     }
-    return res;
+    Ok(res)
 }
 
-fn factorial(n: ast::Num) -> Result<ast::Num> {
+fn factorial(n: ast::Num, limits: &EvalLimits) -> Result<ast::Num> {
     if let Some(top) = to_int(&n) {
+        if top > limits.max_factorial.into() {
+            bail!(EvalError::TooLarge(format!("factorial of {}", top)));
+        }
         let mut res = 1.into();
         let mut iter: num::BigInt = 2.into();
         while iter <= top {
@@ -57,15 +229,31 @@ fn factorial(n: ast::Num) -> Result<ast::Num> {
             iter += 1;
         }
 
-        return Ok(Num::from_integer(res))
+        Ok(Num::from_integer(res))
     } else {
         return Err(simple_error!("Factorial of non-integer {:?}", n))?;
     }
 }
 
-fn exp(n: ast::Num, e: ast::Num) -> Result<ast::Num> {
+fn exp(n: ast::Num, e: ast::Num, limits: &EvalLimits) -> Result<ast::Num> {
     match to_int(&e) {
-        Some(ie) => return Ok(n.pow(ie)),
+        Some(ie) if n.is_zero() && ie < Zero::zero() => Err(EvalError::DivisionByZero)?,
+        Some(ie) => {
+            let mag = ie.magnitude();
+            let base_bits = std::cmp::max(n.numer().bits(), n.denom().bits());
+            // 0, 1 and -1 stay small whatever the exponent
+            if base_bits > 1 {
+                if *mag > limits.max_exponent.into() {
+                    bail!(EvalError::TooLarge(format!("exponent {}", ie)));
+                }
+                // Too many bits to count counts as too many
+                match mag.to_u64().and_then(|m| (base_bits - 1).checked_mul(m)) {
+                    Some(bits) if bits <= limits.max_bits => (),
+                    _ => bail!(EvalError::TooLarge(format!("{} ^ {}", n, ie))),
+                }
+            }
+            Ok(n.pow(ie))
+        }
         None => Err(simple_error!("Exponent to non-integer {:?}", e))?,
     }
 }
@@ -91,13 +279,18 @@ fn bitxor(a: ast::Num, b: ast::Num) -> Result<ast::Num> {
     }
 }
 
-fn bitshift(a: ast::Num, b: ast::Num) -> Result<ast::Num> {
+fn bitshift(a: ast::Num, b: ast::Num, limits: &EvalLimits) -> Result<ast::Num> {
     match (to_int(&a), to_int(&b).and_then(|i| i.to_isize())) {
         (Some(ia), Some(ib)) => {
             if ib > 0 {
+                if ia.bits() + ib as u64 > limits.max_bits {
+                    bail!(EvalError::TooLarge(format!("shift by {}", ib)));
+                }
                 Ok((ia << ib).into())
             } else {
-                Ok((ia >> -ib).into())
+                let shift = ib.checked_neg().ok_or_else(
+                    || simple_error!("Bit shift by {} is too far", ib))?;
+                Ok((ia >> shift).into())
             }
         }
         _ => Err(simple_error!("Bit shift of non-integers"))?,
@@ -122,23 +315,151 @@ mod tests {
 
         assert_eq!(eval(&Number(to_num(5), Digits), &env).unwrap(), to_num(5));
         assert_eq!(eval(&BinOp(Add,
-                               Box::new(Number(to_num(1), Digits)),
-                               Box::new(Number(to_num(2), Words))),
+                               expr(Number(to_num(1), Digits)),
+                               expr(Number(to_num(2), Words))),
                         &env) .unwrap(),
                    to_num(3));
         assert_eq!(eval(&BinOp(Add,
-                               Box::new(Var("i".to_string())),
-                               Box::new(Number(to_num(2), Words))),
+                               expr(Var("i".to_string())),
+                               expr(Number(to_num(2), Words))),
                         &env) .unwrap(),
                    to_num(4));
         assert!(eval(&BinOp(Add,
-                            Box::new(Var("j".to_string())),
-                            Box::new(Number(to_num(2), Words))),
+                            expr(Var("j".to_string())),
+                            expr(Number(to_num(2), Words))),
                      &env) .is_err());
+        assert_eq!(eval(&UnaOp(Neg,
+                               expr(BinOp(Add,
+                                          expr(Number(to_num(3), Digits)),
+                                          expr(Var("i".to_string()))))),
+                        &env) .unwrap(),
+                   to_num(-5));
+    }
+
+    #[test]
+    fn test_eval_ops() {
+        let env = Env::new();
+        let cases = &[(Mod, 100, 7, 2),
+                      (Mod, -7, 3, 2),
+                      (IntDiv, 17, 2, 8),
+                      (IntDiv, -17, 2, -9),
+                      (Eq, 3, 3, 1),
+                      (Ne, 3, 3, 0),
+                      (Lt, 2, 3, 1),
+                      (Ge, 2, 3, 0)];
+
+        for (op, a, b, res) in cases.iter() {
+            let expr = BinOp(*op,
+                             expr(Number(to_num(*a), Digits)),
+                             expr(Number(to_num(*b), Digits)));
+            assert_eq!(eval(&expr, &env).unwrap(), to_num(*res), "{:?}", expr);
+        }
+
+        assert!(eval(&BinOp(Mod,
+                            expr(Number(to_num(1), Digits)),
+                            expr(Number(to_num(0), Digits))),
+                     &env).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let cases = &["9^9^9", "99999!", "1 << 99999999999", "100000d6",
+                      "2 ^ 100000 * 2 ^ 100000", "fib(99999999)"];
+
+        for line in cases.iter() {
+            let expr = crate::parse::best_parse(line).unwrap();
+            let err = eval(&expr, &Env::new()).unwrap_err();
+            assert!(matches!(err.downcast_ref::<EvalError>(), Some(EvalError::TooLarge(_))),
+                    "{:?} gave {:?}", line, err);
+        }
+
+        assert_eq!(eval(&crate::parse::best_parse("1 ^ 99999999999").unwrap(), &Env::new()).unwrap(),
+                   to_num(1));
+
+        // The bit count itself would overflow
+        let limits = EvalLimits { max_exponent: u64::MAX, ..Default::default() };
+        let expr = crate::parse::best_parse("15 ^ 9223372036854775807").unwrap();
+        let err = eval_with(&expr, &Env::new(), &funcs::BUILTINS, &limits).unwrap_err();
+        assert!(matches!(err.downcast_ref::<EvalError>(), Some(EvalError::TooLarge(_))));
+
+        let limits = EvalLimits { max_sequence_arg: 10, ..Default::default() };
+        let expr = crate::parse::best_parse("fib(11)").unwrap();
+        assert!(eval_with(&expr, &Env::new(), &funcs::BUILTINS, &limits).is_err());
+
+        let expr = crate::parse::best_parse("isprime(2 ^ 8000 + 1)").unwrap();
+        let err = eval(&expr, &Env::new()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<EvalError>(), Some(EvalError::TooLarge(_))));
+        let limits = EvalLimits { max_prime_bits: 8, ..Default::default() };
+        let expr = crate::parse::best_parse("isprime(257)").unwrap();
+        assert!(eval_with(&expr, &Env::new(), &funcs::BUILTINS, &limits).is_err());
+        let expr = crate::parse::best_parse("isprime(251)").unwrap();
+        assert_eq!(eval_with(&expr, &Env::new(), &funcs::BUILTINS, &limits).unwrap(), to_num(1));
+
+        let limits = EvalLimits { max_nodes: 3, ..Default::default() };
+        let expr = crate::parse::best_parse("1 + 2 + 3").unwrap();
+        assert!(eval_with(&expr, &Env::new(), &funcs::BUILTINS, &limits).is_err());
+
+        let mut funcs = FunctionRegistry::new();
+        funcs.register("slow", funcs::Arity::Exactly(0), |_| {
+            std::thread::sleep(Duration::from_millis(20));
+            Ok(to_num(1))
+        });
+        let limits = EvalLimits { timeout_ms: 10, ..Default::default() };
+        let expr = crate::parse::best_parse("slow() + 1").unwrap();
+        let err = eval_with(&expr, &Env::new(), &funcs, &limits).unwrap_err();
+        assert_eq!(err.downcast_ref::<EvalError>(), Some(&EvalError::TimedOut));
+    }
+
+    #[test]
+    fn test_eval_with() {
+        let env = Env::new();
+        let mut funcs = FunctionRegistry::with_builtins();
+        funcs.register("double", funcs::Arity::Exactly(1), |a| Ok(&a[0] * to_num(2)));
+
+        let call = Funcall("double".to_string(),
+                           vec![expr(Funcall("sqrt".to_string(),
+                                             vec![expr(Number(to_num(16), Digits))]))]);
+        assert_eq!(eval_with(&call, &env, &funcs, &Default::default()).unwrap(), to_num(8));
+        assert!(eval(&call, &env).is_err());
     }
 
     #[test]
     fn test_roll() {
-        println!("1d2 -> {}", roll(5, 2));
+        let limits = EvalLimits::default();
+        println!("1d2 -> {}", roll(5, 2, &limits).unwrap());
+        assert!(roll(1, 0, &limits).is_err());
+    }
+
+    #[test]
+    fn test_hostile_inputs() {
+        use EvalError::*;
+        // Errors without a variant of their own are only checked to fail
+        let failures = &[("1/0", Some(DivisionByZero)), ("1 / (2 - 2)", Some(DivisionByZero)),
+                         ("0 ^ (0 - 1)", Some(DivisionByZero)), ("d0", Some(NoSides)),
+                         ("5d0", Some(NoSides)), ("5 // 0", Some(DivisionByZero)),
+                         ("5 % 0", Some(DivisionByZero)), ("mod(1, 0)", Some(DivisionByZero)),
+                         ("1 << (0 - 9223372036854775808)", None), ("1 >> 9223372036854775808", None),
+                         ("sqrt(-1)", None), ("fib(-1)", None), ("choose(0 - 1, 2)", None),
+                         ("max()", None), ("3.5!", None)];
+        for (line, want) in failures.iter() {
+            let expr = crate::parse::best_parse(line).unwrap_or_else(|| panic!("No parse of {:?}", line));
+            let err = eval(&expr, &Env::new()).expect_err(line);
+            if let Some(want) = want {
+                assert_eq!(err.downcast_ref::<EvalError>(), Some(want), "{:?}", line);
+            }
+        }
+
+        let unparseable = &["1d99999999999999999999", "99999999999999999999d6", "((((((", "))))",
+                            "", "0x", ".", "-", "!!!", "1 +", "\u{0}", "１２３", "0b102"];
+        for line in unparseable.iter() {
+            assert_eq!(crate::parse::best_parse(line), None, "{:?}", line);
+        }
+
+        let div = eval(&BinOp(Div,
+                              expr(Number(to_num(1), Digits)),
+                              expr(Number(to_num(0), Digits))),
+                       &Env::new());
+        assert_eq!(div.unwrap_err().downcast_ref::<EvalError>(),
+                   Some(&EvalError::DivisionByZero));
     }
 }
@@ -0,0 +1,337 @@
+use crate::ast::{Num, to_num};
+use crate::eval::{self, EvalError, EvalLimits};
+use crate::types::Result;
+use std::collections::HashMap;
+use num::{BigInt, Integer, One, Signed, ToPrimitive, Zero};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, n: usize) -> bool {
+        match self {
+            Arity::Exactly(a) => n == *a,
+            Arity::AtLeast(a) => n >= *a,
+        }
+    }
+}
+
+impl std::fmt::Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Arity::Exactly(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+        }
+    }
+}
+
+pub type Func = Box<dyn Fn(&[Num], &EvalLimits) -> Result<Num> + Send + Sync>;
+
+pub struct Function {
+    pub arity: Arity,
+    pub func: Func,
+}
+
+/// Functions that `Funcall` nodes may call, by name.
+#[derive(Default)]
+pub struct FunctionRegistry {
+    funcs: HashMap<String, Function>,
+}
+
+impl FunctionRegistry {
+    /// An empty registry, without even the built-ins.
+    pub fn new() -> FunctionRegistry {
+        FunctionRegistry { funcs: HashMap::new() }
+    }
+
+    pub fn with_builtins() -> FunctionRegistry {
+        let mut reg = FunctionRegistry::new();
+        reg.register("sqrt", Arity::Exactly(1), sqrt);
+        reg.register("abs", Arity::Exactly(1), |a| Ok(a[0].abs()));
+        reg.register("floor", Arity::Exactly(1), |a| Ok(a[0].floor()));
+        reg.register("ceil", Arity::Exactly(1), |a| Ok(a[0].ceil()));
+        reg.register("round", Arity::Exactly(1), |a| Ok(a[0].round()));
+        reg.register("min", Arity::AtLeast(1), |a| Ok(a.iter().min().unwrap().clone()));
+        reg.register("max", Arity::AtLeast(1), |a| Ok(a.iter().max().unwrap().clone()));
+        reg.register("gcd", Arity::AtLeast(2), gcd);
+        reg.register("lcm", Arity::AtLeast(2), lcm);
+        reg.register("mod", Arity::Exactly(2), |a| eval::modulo(&a[0], &a[1]));
+        reg.register_limited("choose", Arity::Exactly(2), choose);
+        reg.register_limited("fib", Arity::Exactly(1), fib);
+        reg.register_limited("isprime", Arity::Exactly(1), isprime);
+        reg.register("digitsum", Arity::Exactly(1), digitsum);
+        reg
+    }
+
+    /// Adds a function, replacing any existing one of the same name.
+    /// `func` is only called with an argument count `arity` accepts.
+    pub fn register<F>(&mut self, name: &str, arity: Arity, func: F)
+        where F: Fn(&[Num]) -> Result<Num> + Send + Sync + 'static
+    {
+        self.register_limited(name, arity, move |args, _| func(args));
+    }
+
+    /// Like `register`, for a function that needs to respect the
+    /// evaluation's limits.
+    pub fn register_limited<F>(&mut self, name: &str, arity: Arity, func: F)
+        where F: Fn(&[Num], &EvalLimits) -> Result<Num> + Send + Sync + 'static
+    {
+        self.funcs.insert(name.to_string(), Function { arity, func: Box::new(func) });
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Option<Function> {
+        self.funcs.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.funcs.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.funcs.keys().map(|k| k.as_str())
+    }
+
+    pub fn call(&self, name: &str, args: &[Num]) -> Result<Num> {
+        self.call_with(name, args, &Default::default())
+    }
+
+    /// Like `call`, but under `limits` rather than the defaults.
+    pub fn call_with(&self, name: &str, args: &[Num], limits: &EvalLimits) -> Result<Num> {
+        let function = self.get(name).ok_or_else(
+            || simple_error!("Unknown function '{}'", name))?;
+
+        if !function.arity.accepts(args.len()) {
+            bail!("Function '{}' expects {} arguments, got {}",
+                  name, function.arity, args.len());
+        }
+
+        (function.func)(args, limits)
+    }
+}
+
+lazy_static! {
+    pub static ref BUILTINS: FunctionRegistry = FunctionRegistry::with_builtins();
+}
+
+// fib and choose do work linear in their argument, which the
+// evaluator can't see coming, so they get a cap of their own.
+fn bounded_arg(n: &Num, func: &str, limits: &EvalLimits) -> Result<BigInt> {
+    let i = nonneg_arg(n, func)?;
+    if i > limits.max_sequence_arg.into() {
+        bail!(EvalError::TooLarge(format!("{}({})", func, i)));
+    }
+    Ok(i)
+}
+
+fn int_arg(n: &Num, func: &str) -> Result<BigInt> {
+    if n.is_integer() {
+        Ok(n.to_integer())
+    } else {
+        Err(simple_error!("{} of non-integer {}", func, n))?
+    }
+}
+
+fn nonneg_arg(n: &Num, func: &str) -> Result<BigInt> {
+    let i = int_arg(n, func)?;
+    if i.is_negative() {
+        bail!("{} of negative number {}", func, n);
+    }
+    Ok(i)
+}
+
+fn exact_sqrt(i: &BigInt) -> Option<BigInt> {
+    let root = i.sqrt();
+    if &root * &root == *i { Some(root) } else { None }
+}
+
+fn sqrt(args: &[Num]) -> Result<Num> {
+    let n = &args[0];
+    if n.is_negative() {
+        bail!("sqrt of negative number {}", n);
+    }
+    match (exact_sqrt(n.numer()), exact_sqrt(n.denom())) {
+        (Some(num), Some(den)) => Ok(Num::new(num, den)),
+        _ => Err(simple_error!("sqrt of non-square {}", n))?,
+    }
+}
+
+fn gcd(args: &[Num]) -> Result<Num> {
+    let mut res = BigInt::zero();
+    for a in args {
+        res = res.gcd(&int_arg(a, "gcd")?);
+    }
+    Ok(Num::from_integer(res))
+}
+
+fn lcm(args: &[Num]) -> Result<Num> {
+    let mut res = BigInt::one();
+    for a in args {
+        res = res.lcm(&int_arg(a, "lcm")?);
+    }
+    Ok(Num::from_integer(res))
+}
+
+fn choose(args: &[Num], limits: &EvalLimits) -> Result<Num> {
+    let n = bounded_arg(&args[0], "choose", limits)?;
+    let k = nonneg_arg(&args[1], "choose")?;
+    if k > n {
+        return Ok(to_num(0));
+    }
+    let k = std::cmp::min(k.clone(), &n - &k);
+
+    let mut res = BigInt::one();
+    let mut i = BigInt::zero();
+    while i < k {
+        res = res * (&n - &i) / (&i + 1);
+        i += 1;
+    }
+    Ok(Num::from_integer(res))
+}
+
+fn fib(args: &[Num], limits: &EvalLimits) -> Result<Num> {
+    let n = bounded_arg(&args[0], "fib", limits)?;
+    let (mut a, mut b) = (BigInt::zero(), BigInt::one());
+    let mut i = BigInt::zero();
+    while i < n {
+        let next = &a + &b;
+        a = std::mem::replace(&mut b, next);
+        i += 1;
+    }
+    Ok(Num::from_integer(a))
+}
+
+fn isprime(args: &[Num], limits: &EvalLimits) -> Result<Num> {
+    let n = int_arg(&args[0], "isprime")?;
+    if n.bits() > limits.max_prime_bits {
+        bail!(EvalError::TooLarge(format!("isprime of a {}-bit number", n.bits())));
+    }
+    Ok(to_num(if is_prime(&n) { 1 } else { 0 }))
+}
+
+// Miller-Rabin over the first twelve primes, which is exact below
+// 3.3 * 10^24 and overwhelmingly likely above that.
+pub fn is_prime(n: &BigInt) -> bool {
+    const BASES: [u32; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+    if *n < BigInt::from(2) {
+        return false;
+    }
+    for &p in BASES.iter() {
+        if *n == BigInt::from(p) {
+            return true;
+        }
+        if n.is_multiple_of(&BigInt::from(p)) {
+            return false;
+        }
+    }
+
+    let n1: BigInt = n - 1;
+    let mut d = n1.clone();
+    let mut s = 0;
+    while d.is_even() {
+        d >>= 1;
+        s += 1;
+    }
+
+    'witness: for &a in BASES.iter() {
+        let mut x = BigInt::from(a).modpow(&d, n);
+        if x.is_one() || x == n1 {
+            continue;
+        }
+        for _ in 1..s {
+            x = x.modpow(&BigInt::from(2), n);
+            if x == n1 {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+fn digitsum(args: &[Num]) -> Result<Num> {
+    let n = int_arg(&args[0], "digitsum")?;
+    let sum: u32 = n.abs().to_str_radix(10).chars()
+        .map(|c| c.to_digit(10).unwrap())
+        .sum();
+    Ok(to_num(sum.to_i64().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nums(is: &[i64]) -> Vec<Num> {
+        is.iter().map(|i| to_num(*i)).collect()
+    }
+
+    #[test]
+    fn test_builtins() {
+        let cases: &[(&str, &[i64], i64)] = &[
+            ("sqrt", &[144], 12),
+            ("abs", &[-7], 7),
+            ("min", &[4, 2, 9], 2),
+            ("max", &[4, 2, 9], 9),
+            ("gcd", &[12, 18], 6),
+            ("lcm", &[4, 6], 12),
+            ("mod", &[-7, 3], 2),
+            ("choose", &[5, 2], 10),
+            ("choose", &[2, 5], 0),
+            ("fib", &[12], 144),
+            ("fib", &[0], 0),
+            ("isprime", &[97], 1),
+            ("isprime", &[91], 0),
+            ("isprime", &[1], 0),
+            ("digitsum", &[-1234], 10),
+        ];
+
+        for (name, args, res) in cases.iter() {
+            assert_eq!(BUILTINS.call(name, &nums(args)).unwrap(), to_num(*res),
+                       "{}({:?})", name, args);
+        }
+
+        assert_eq!(BUILTINS.call("sqrt", &[to_num(9) / to_num(4)]).unwrap(), to_num(3) / to_num(2));
+        assert_eq!(BUILTINS.call("round", &[to_num(5) / to_num(2)]).unwrap(), to_num(3));
+        assert_eq!(BUILTINS.call("floor", &[to_num(-5) / to_num(2)]).unwrap(), to_num(-3));
+        assert_eq!(BUILTINS.call("ceil", &[to_num(5) / to_num(2)]).unwrap(), to_num(3));
+    }
+
+    #[test]
+    fn test_builtins_fail() {
+        let cases: &[(&str, &[i64])] = &[
+            ("sqrt", &[2]),
+            ("sqrt", &[-4]),
+            ("sqrt", &[1, 2]),
+            ("min", &[]),
+            ("gcd", &[3]),
+            ("mod", &[3, 0]),
+            ("fib", &[-1]),
+            ("fib", &[1_000_000_000]),
+            ("choose", &[1_000_000_000, 500_000_000]),
+            ("nosuchfunc", &[1]),
+        ];
+
+        for (name, args) in cases.iter() {
+            assert!(BUILTINS.call(name, &nums(args)).is_err(), "{}({:?}) should fail", name, args);
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let mut reg = FunctionRegistry::new();
+        assert!(reg.call("sqrt", &nums(&[4])).is_err());
+
+        let offset = to_num(96);
+        reg.register("ord", Arity::Exactly(1), move |a| Ok(&a[0] + &offset));
+        assert_eq!(reg.call("ord", &nums(&[1])).unwrap(), to_num(97));
+        assert!(reg.call("ord", &nums(&[1, 2])).is_err());
+
+        reg.register("ord", Arity::AtLeast(0), |a| Ok(to_num(a.len() as i64)));
+        assert_eq!(reg.call("ord", &nums(&[1, 2])).unwrap(), to_num(2));
+        assert!(reg.unregister("ord").is_some());
+        assert!(reg.call("ord", &[]).is_err());
+    }
+}
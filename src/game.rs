@@ -0,0 +1,519 @@
+use crate::ast::Num;
+use crate::eval::{self, EvalLimits};
+use crate::funcs;
+use crate::mode::{CountingMode, Step};
+use crate::parse::{self, Candidate};
+use crate::rank::Ranking;
+use crate::rules::RuleSet;
+use crate::stats::{Stats, UserStats};
+use crate::store::Store;
+use crate::types::Result;
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+pub type UserId = String;
+
+/// How a `CountingSession` treats the ways a count can go wrong.
+#[derive(Debug, Clone)]
+pub struct GameRules {
+    /// What's being counted
+    pub mode: Arc<dyn CountingMode>,
+    /// Whether one user may count twice in a row
+    pub allow_consecutive: bool,
+    /// Whether a wrong number sends the count back to the start
+    pub reset_on_wrong: bool,
+    /// Whether counting twice in a row sends the count back to the start
+    pub reset_on_consecutive: bool,
+    /// Whether a message without a number sends the count back to the
+    /// start, rather than being taken as chatter
+    pub reset_on_no_number: bool,
+    /// How numbers may be written
+    pub expr_rules: RuleSet,
+    pub ranking: Ranking,
+    pub limits: EvalLimits,
+}
+
+impl Default for GameRules {
+    fn default() -> GameRules {
+        GameRules {
+            mode: Arc::new(Step::default()),
+            allow_consecutive: false,
+            reset_on_wrong: true,
+            reset_on_consecutive: true,
+            reset_on_no_number: false,
+            expr_rules: RuleSet::default(),
+            ranking: Ranking::default(),
+            limits: EvalLimits::default(),
+        }
+    }
+}
+
+/// What a message did to the count.
+#[derive(Serialize, Debug, Display, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Outcome {
+    #[display(fmt = "{} counted {}", user, count)]
+    Correct {
+        user: UserId,
+        #[serde(with = "num_string")]
+        count: Num,
+    },
+    #[display(fmt = "{} said {}, but the next number was {}", user, got, expected)]
+    WrongNumber {
+        user: UserId,
+        #[serde(with = "num_string")]
+        expected: Num,
+        #[serde(with = "num_string")]
+        got: Num,
+    },
+    #[display(fmt = "{} can't count that: {}", user, reason)]
+    Rejected { user: UserId, reason: String },
+    #[display(fmt = "No number found")]
+    NoNumber,
+    #[display(fmt = "{} counted twice in a row", user)]
+    SameUserTwice { user: UserId },
+    #[display(fmt = "The count was reset after reaching {}", reached)]
+    CountReset {
+        #[serde(with = "num_string")]
+        reached: Num,
+    },
+}
+
+impl Outcome {
+    pub fn is_correct(&self) -> bool {
+        matches!(self, Outcome::Correct { .. })
+    }
+}
+
+/// Everything about a count worth keeping between runs. See `store`
+/// for places to keep it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GameState {
+    /// The last number counted, or one before the start if none has been
+    #[serde(with = "num_string")]
+    pub count: Num,
+    pub last_user: Option<UserId>,
+    /// How many numbers have been counted since the last reset
+    #[serde(default)]
+    pub run: u64,
+    /// The number the longest run reached
+    #[serde(with = "num_string")]
+    pub high_score: Num,
+    /// How many numbers the longest run counted
+    #[serde(default)]
+    pub best_run: u64,
+    #[serde(rename = "users")]
+    pub stats: Stats,
+}
+
+impl GameState {
+    pub fn new(rules: &GameRules) -> GameState {
+        GameState {
+            count: rules.mode.origin(),
+            last_user: None,
+            run: 0,
+            high_score: rules.mode.origin(),
+            best_run: 0,
+            stats: Stats::new(),
+        }
+    }
+}
+
+// Numbers as their decimal "n" or "n/d" form, so stored state stays
+// readable
+mod num_string {
+    use crate::ast::Num;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(n: &Num, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(n)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Num, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(|_| de::Error::custom(format!("bad number {:?}", s)))
+    }
+}
+
+/// A message a session was sent, and what came of it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub user: UserId,
+    pub message: String,
+    pub outcomes: Vec<Outcome>,
+}
+
+/// How many events a session remembers.
+pub const HISTORY_LEN: usize = 100;
+
+/// The state of one counting channel. Feed it each message with
+/// `submit`, in the order they were sent.
+#[derive(Debug, Clone)]
+pub struct CountingSession {
+    rules: GameRules,
+    state: GameState,
+    // Not part of GameState, so not kept between runs
+    history: VecDeque<Event>,
+}
+
+impl CountingSession {
+    pub fn new(rules: GameRules) -> CountingSession {
+        let state = GameState::new(&rules);
+        CountingSession::with_state(rules, state)
+    }
+
+    /// Picks up a count where it was left off.
+    pub fn with_state(rules: GameRules, state: GameState) -> CountingSession {
+        CountingSession { rules, state, history: VecDeque::new() }
+    }
+
+    pub fn rules(&self) -> &GameRules {
+        &self.rules
+    }
+
+    pub fn state(&self) -> &GameState {
+        &self.state
+    }
+
+    pub fn count(&self) -> &Num {
+        &self.state.count
+    }
+
+    pub fn expected(&self) -> Num {
+        self.rules.mode.next(&self.state.count)
+    }
+
+    pub fn last_user(&self) -> Option<&str> {
+        self.state.last_user.as_deref()
+    }
+
+    pub fn high_score(&self) -> &Num {
+        &self.state.high_score
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.state.stats
+    }
+
+    pub fn user_stats(&self, user: &str) -> Option<&UserStats> {
+        self.state.stats.get(user)
+    }
+
+    /// The last `HISTORY_LEN` messages submitted, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &Event> {
+        self.history.iter()
+    }
+
+    /// Judges `message` from `user`. A failure that ends the count is
+    /// followed by `Outcome::CountReset`.
+    pub fn submit(&mut self, user: &str, message: &str) -> Vec<Outcome> {
+        let outcomes = self.judge(user, message);
+
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(Event {
+            user: user.to_string(),
+            message: message.to_string(),
+            outcomes: outcomes.clone(),
+        });
+        outcomes
+    }
+
+    fn judge(&mut self, user: &str, message: &str) -> Vec<Outcome> {
+        let (candidate, got) = match self.read_number(message) {
+            Some(read) => read,
+            None if self.rules.reset_on_no_number => return vec![Outcome::NoNumber, self.reset()],
+            None => return vec![Outcome::NoNumber],
+        };
+
+        if !self.rules.allow_consecutive && self.last_user() == Some(user) {
+            self.state.stats.record_expr(user, message, Some(&candidate.expr), false);
+            let outcome = Outcome::SameUserTwice { user: user.to_string() };
+            return if self.rules.reset_on_consecutive {
+                vec![outcome, self.reset()]
+            } else {
+                vec![outcome]
+            };
+        }
+
+        let wrong = if let Err(violation) = self.rules.expr_rules.check_candidate(&candidate, message) {
+            Some(Outcome::Rejected { user: user.to_string(), reason: violation.to_string() })
+        } else if let Some(reason) = self.rules.mode.check_expr(&candidate.expr) {
+            Some(Outcome::Rejected { user: user.to_string(), reason })
+        } else if !self.rules.mode.accepts(&self.state.count, &got) {
+            Some(Outcome::WrongNumber { user: user.to_string(), expected: self.expected(), got: got.clone() })
+        } else {
+            None
+        };
+        if let Some(outcome) = wrong {
+            self.state.stats.record_expr(user, message, Some(&candidate.expr), false);
+            return if self.rules.reset_on_wrong {
+                vec![outcome, self.reset()]
+            } else {
+                vec![outcome]
+            };
+        }
+
+        self.state.stats.record_expr(user, message, Some(&candidate.expr), true);
+        self.state.run += 1;
+        if self.state.run > self.state.best_run {
+            self.state.best_run = self.state.run;
+            self.state.high_score = got.clone();
+        }
+        self.state.count = got.clone();
+        self.state.last_user = Some(user.to_string());
+        vec![Outcome::Correct { user: user.to_string(), count: got }]
+    }
+
+    /// Starts the count over, whatever it had reached.
+    pub fn reset(&mut self) -> Outcome {
+        let reached = std::mem::replace(&mut self.state.count, self.rules.mode.origin());
+        self.state.last_user = None;
+        self.state.run = 0;
+        Outcome::CountReset { reached }
+    }
+
+    // Messages that don't evaluate, say for naming a variable, count
+    // as having no number at all
+    fn read_number(&self, message: &str) -> Option<(Candidate, Num)> {
+        let ranker = self.rules.ranking.ranker_with(&funcs::BUILTINS, &self.rules.limits);
+        let candidate = parse::best_candidate_with(message, &*ranker)?;
+        let n = eval::eval_with(&candidate.expr, &Default::default(), &funcs::BUILTINS,
+                                &self.rules.limits).ok()?;
+        Some((candidate, n))
+    }
+}
+
+/// Failures of `Sessions` that callers may want to tell apart.
+#[derive(Debug, Display, PartialEq)]
+pub enum SessionError {
+    #[display(fmt = "No session {}", _0)]
+    NoSession(String),
+    #[display(fmt = "Session {} already exists", _0)]
+    Exists(String),
+}
+
+impl std::error::Error for SessionError {}
+
+/// Counting sessions by id, shareable between threads. Changes are
+/// saved to the store as they're made, and only kept once they have
+/// been.
+pub struct Sessions {
+    // Each session has its own lock, so a slow message only holds up
+    // its own session
+    sessions: Mutex<HashMap<String, Arc<Mutex<CountingSession>>>>,
+    store: Arc<dyn Store>,
+    defaults: GameRules,
+}
+
+impl Sessions {
+    pub fn new(store: Arc<dyn Store>) -> Sessions {
+        Sessions::with_defaults(store, GameRules::default())
+    }
+
+    /// Like `new`, but with `defaults` as the rules new sessions
+    /// start from.
+    pub fn with_defaults(store: Arc<dyn Store>, defaults: GameRules) -> Sessions {
+        Sessions { sessions: Mutex::new(HashMap::new()), store, defaults }
+    }
+
+    /// The rules new sessions start from, for requests to override.
+    pub fn defaults(&self) -> &GameRules {
+        &self.defaults
+    }
+
+    /// Starts session `id` under `rules`, picking up its saved state
+    /// if the store has any.
+    pub fn create(&self, id: &str, rules: GameRules) -> Result<()> {
+        if self.sessions.lock().unwrap().contains_key(id) {
+            bail!(SessionError::Exists(id.to_string()));
+        }
+
+        let session = match self.store.load(id)? {
+            Some(state) => CountingSession::with_state(rules, state),
+            None => CountingSession::new(rules),
+        };
+        // Someone else may have got there while the store was loading
+        match self.sessions.lock().unwrap().entry(id.to_string()) {
+            Entry::Occupied(_) => bail!(SessionError::Exists(id.to_string())),
+            Entry::Vacant(entry) => entry.insert(Arc::new(Mutex::new(session))),
+        };
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Result<Arc<Mutex<CountingSession>>> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id).ok_or_else(|| SessionError::NoSession(id.to_string()))?;
+        Ok(session.clone())
+    }
+
+    /// Calls `f` with session `id`.
+    pub fn with<R>(&self, id: &str, f: impl FnOnce(&CountingSession) -> R) -> Result<R> {
+        let session = self.get(id)?;
+        let session = session.lock().unwrap();
+        Ok(f(&session))
+    }
+
+    /// Calls `f` with a copy of session `id`, and keeps what it did
+    /// only if that can be saved.
+    pub fn update<R>(&self, id: &str, f: impl FnOnce(&mut CountingSession) -> R) -> Result<R> {
+        let session = self.get(id)?;
+        let mut session = session.lock().unwrap();
+        let mut updated = session.clone();
+        let res = f(&mut updated);
+        self.store.save(id, updated.state())?;
+        *session = updated;
+        Ok(res)
+    }
+
+    pub fn submit(&self, id: &str, user: &str, message: &str) -> Result<Vec<Outcome>> {
+        self.update(id, |s| s.submit(user, message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::to_num;
+
+    #[test]
+    fn test_counting() {
+        let mut game = CountingSession::new(Default::default());
+
+        assert_eq!(game.submit("a", "1"), vec![Outcome::Correct { user: "a".into(), count: to_num(1) }]);
+        assert!(game.submit("b", "one plus one")[0].is_correct());
+        assert!(game.submit("a", "I think it's 9/3, right?")[0].is_correct());
+        assert_eq!(game.submit("b", "nice"), vec![Outcome::NoNumber]);
+        assert_eq!(game.count(), &to_num(3));
+
+        assert_eq!(game.submit("b", "2*2"),
+                   vec![Outcome::Correct { user: "b".into(), count: to_num(4) }]);
+        assert_eq!(game.submit("b", "5"),
+                   vec![Outcome::SameUserTwice { user: "b".into() },
+                        Outcome::CountReset { reached: to_num(4) }]);
+        assert_eq!(game.expected(), to_num(1));
+        assert_eq!(game.last_user(), None);
+
+        assert!(game.submit("b", "1")[0].is_correct());
+        assert_eq!(game.submit("a", "3"),
+                   vec![Outcome::WrongNumber { user: "a".into(), expected: to_num(2), got: to_num(3) },
+                        Outcome::CountReset { reached: to_num(1) }]);
+
+        assert_eq!(game.high_score(), &to_num(4));
+        let stats = |user| game.user_stats(user).map(|s| (s.correct, s.failures));
+        assert_eq!(stats("a"), Some((2, 1)));
+        assert_eq!(stats("b"), Some((3, 1)));
+
+        let resumed = CountingSession::with_state(Default::default(), game.state().clone());
+        assert_eq!(resumed.state(), game.state());
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = GameRules {
+            mode: Arc::new(Step { start: to_num(10), step: to_num(1) }),
+            allow_consecutive: true,
+            reset_on_wrong: false,
+            reset_on_no_number: true,
+            ..Default::default()
+        };
+        let mut game = CountingSession::new(rules);
+
+        assert!(game.submit("a", "10")[0].is_correct());
+        assert!(game.submit("a", "11")[0].is_correct());
+        assert_eq!(game.submit("a", "13").len(), 1);
+        assert_eq!(game.count(), &to_num(11));
+        assert_eq!(game.submit("a", "1/0"),
+                   vec![Outcome::NoNumber, Outcome::CountReset { reached: to_num(11) }]);
+        assert_eq!(game.expected(), to_num(10));
+    }
+
+    #[test]
+    fn test_modes() {
+        let rules = GameRules {
+            mode: Arc::new(crate::mode::BaseN::new(2).unwrap()),
+            ..Default::default()
+        };
+        let mut game = CountingSession::new(rules);
+
+        assert!(game.submit("a", "0b1")[0].is_correct());
+        assert_eq!(game.submit("b", "2"),
+                   vec![Outcome::Rejected { user: "b".into(), reason: "Numbers must be written in binary".into() },
+                        Outcome::CountReset { reached: to_num(1) }]);
+
+        let rules = GameRules { mode: Arc::new(Step::countdown(100)), ..Default::default() };
+        let mut game = CountingSession::new(rules);
+        for (user, msg) in [("a", "100"), ("b", "99"), ("a", "98"), ("b", "1"), ("a", "100")] {
+            game.submit(user, msg);
+        }
+        assert_eq!(game.state().best_run, 3);
+        assert_eq!(game.high_score(), &to_num(98));
+    }
+
+    #[test]
+    fn test_expr_rules() {
+        let rules = GameRules {
+            expr_rules: RuleSet { min_size: Some(2), ..Default::default() },
+            reset_on_wrong: false,
+            ..Default::default()
+        };
+        let mut game = CountingSession::new(rules);
+
+        assert_eq!(game.submit("a", "1"),
+                   vec![Outcome::Rejected { user: "a".into(),
+                                            reason: "Too simple: needs at least 2 parts, has 1".into() }]);
+        assert!(game.submit("a", "0 + 1")[0].is_correct());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let store = Arc::new(crate::store::MemoryStore::new());
+        let sessions = Sessions::new(store.clone());
+
+        sessions.create("chan", Default::default()).unwrap();
+        assert!(sessions.create("chan", Default::default()).is_err());
+        assert!(sessions.submit("chan", "a", "1").unwrap()[0].is_correct());
+        assert!(sessions.submit("chan", "b", "2").unwrap()[0].is_correct());
+
+        let err = sessions.submit("nope", "a", "1").unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&SessionError::NoSession("nope".into())));
+
+        let history: Vec<_> = sessions.with("chan", |s| s.history().cloned().collect()).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].message, "2");
+
+        // A change that can't be saved isn't kept
+        struct Broken;
+        impl Store for Broken {
+            fn load(&self, _: &str) -> Result<Option<GameState>> { Ok(None) }
+            fn save(&self, _: &str, _: &GameState) -> Result<()> { bail!("disk full") }
+        }
+        let broken = Sessions::new(Arc::new(Broken));
+        broken.create("chan", Default::default()).unwrap();
+        assert!(broken.submit("chan", "a", "1").is_err());
+        assert_eq!(broken.with("chan", |s| (s.expected(), s.history().count())).unwrap(), (to_num(1), 0));
+
+        // Saved state is picked up by a new set of sessions
+        let sessions = Sessions::new(store);
+        sessions.create("chan", Default::default()).unwrap();
+        assert_eq!(sessions.with("chan", |s| s.expected()).unwrap(), to_num(3));
+    }
+
+    #[test]
+    fn test_upgraded_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("v1.json");
+        std::fs::write(&path, r#"{"version": 1, "sessions": {"chan": {
+            "count": "12", "last_user": "a", "high_score": "20", "users": {}}}}"#).unwrap();
+        let sessions = Sessions::new(Arc::new(crate::store::JsonStore::open(&path).unwrap()));
+        sessions.create("chan", Default::default()).unwrap();
+
+        // A run shorter than the one that set the high score leaves it be
+        assert!(sessions.submit("chan", "b", "13").unwrap()[0].is_correct());
+        assert_eq!(sessions.with("chan", |s| s.high_score().clone()).unwrap(), to_num(20));
+    }
+}
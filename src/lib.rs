@@ -3,9 +3,18 @@
 #[macro_use] extern crate lazy_static;
 
 pub mod parse;
+pub mod rank;
+pub mod game;
+pub mod mode;
+pub mod rules;
+pub mod stats;
+pub mod metrics;
+pub mod store;
+pub mod protocol;
 pub mod eval;
+pub mod funcs;
 pub mod ast;
 pub mod util;
 pub mod types;
 
-lalrpop_mod!(pub grammar);
+lalrpop_mod!(#[allow(clippy::all)] pub grammar);
@@ -0,0 +1,173 @@
+use crate::ast::Node;
+use crate::stats;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How an evaluation request ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RequestOutcome {
+    Good,
+    Bad,
+    NoParse,
+}
+
+impl RequestOutcome {
+    fn label(self) -> &'static str {
+        match self {
+            RequestOutcome::Good => "good",
+            RequestOutcome::Bad => "bad",
+            RequestOutcome::NoParse => "no_parse",
+        }
+    }
+}
+
+const OUTCOMES: [RequestOutcome; 3] = [RequestOutcome::Good, RequestOutcome::Bad, RequestOutcome::NoParse];
+
+// Bucket bounds, in seconds for the latencies
+const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+const CANDIDATE_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0];
+
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations in each bucket alone, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|b| value <= *b) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut seen = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            seen += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, seen);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, self.sum, name, self.count);
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    outcomes: BTreeMap<RequestOutcome, u64>,
+    parse: Histogram,
+    eval: Histogram,
+    candidates: Histogram,
+    ops: BTreeMap<String, u64>,
+}
+
+/// Counts of what the evaluator has been doing, for Prometheus to
+/// scrape.
+#[derive(Debug)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            inner: Mutex::new(Inner {
+                outcomes: BTreeMap::new(),
+                parse: Histogram::new(LATENCY_BUCKETS),
+                eval: Histogram::new(LATENCY_BUCKETS),
+                candidates: Histogram::new(CANDIDATE_BUCKETS),
+                ops: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Records a parse that took `elapsed` and chose `expr`, if any, out
+    /// of `candidates` candidates.
+    pub fn record_parse(&self, elapsed: Duration, candidates: usize, expr: Option<&Node>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.parse.observe(elapsed.as_secs_f64());
+        inner.candidates.observe(candidates as f64);
+        if let Some(expr) = expr {
+            stats::count_ops(expr, &mut inner.ops);
+        }
+    }
+
+    pub fn record_eval(&self, elapsed: Duration) {
+        self.inner.lock().unwrap().eval.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_outcome(&self, outcome: RequestOutcome) {
+        *self.inner.lock().unwrap().outcomes.entry(outcome).or_default() += 1;
+    }
+
+    /// Everything recorded so far, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP counter_parser_requests_total Evaluation requests, by outcome.\n\
+                      # TYPE counter_parser_requests_total counter\n");
+        for outcome in OUTCOMES {
+            let _ = writeln!(out, "counter_parser_requests_total{{outcome=\"{}\"}} {}",
+                             outcome.label(), inner.outcomes.get(&outcome).unwrap_or(&0));
+        }
+
+        inner.parse.render(&mut out, "counter_parser_parse_seconds", "Time spent finding the best parse.");
+        inner.eval.render(&mut out, "counter_parser_eval_seconds", "Time spent evaluating the best parse.");
+        inner.candidates.render(&mut out, "counter_parser_parse_candidates",
+                                "Candidate parses the best was chosen from.");
+
+        out.push_str("# HELP counter_parser_binops_total Operators seen in best parses.\n\
+                      # TYPE counter_parser_binops_total counter\n");
+        for (op, count) in inner.ops.iter() {
+            let _ = writeln!(out, "counter_parser_binops_total{{op=\"{}\"}} {}", op, count);
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::best_parse;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        metrics.record_parse(Duration::from_millis(2), 3, Some(&best_parse("1 + 2 * 3 + 4").unwrap()));
+        metrics.record_eval(Duration::from_micros(50));
+        metrics.record_outcome(RequestOutcome::Good);
+        metrics.record_outcome(RequestOutcome::NoParse);
+
+        let text = metrics.render();
+        for line in ["counter_parser_requests_total{outcome=\"good\"} 1",
+                     "counter_parser_requests_total{outcome=\"bad\"} 0",
+                     "counter_parser_parse_seconds_bucket{le=\"0.001\"} 0",
+                     "counter_parser_parse_seconds_bucket{le=\"0.005\"} 1",
+                     "counter_parser_eval_seconds_count 1",
+                     "counter_parser_parse_candidates_bucket{le=\"2\"} 0",
+                     "counter_parser_parse_candidates_bucket{le=\"4\"} 1",
+                     "counter_parser_binops_total{op=\"+\"} 2",
+                     "counter_parser_binops_total{op=\"*\"} 1"] {
+            assert!(text.lines().any(|l| l == line), "Missing {:?} in:\n{}", line, text);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::ast::*;
+use crate::funcs;
+use crate::types::Result;
+use num::{BigInt, One, Signed};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::sync::Arc;
+
+/// What a count counts: which number comes next, and what a message
+/// has to look like to count it.
+pub trait CountingMode: Debug + Send + Sync {
+    /// The count before anything has been counted. The first number
+    /// is `next(&origin())`.
+    fn origin(&self) -> Num;
+
+    /// The number that should follow `prev`.
+    fn next(&self, prev: &Num) -> Num;
+
+    /// Whether `got` may follow `prev`.
+    fn accepts(&self, prev: &Num, got: &Num) -> bool {
+        *got == self.next(prev)
+    }
+
+    /// Why `expr` can't count in this mode whatever its value, if it
+    /// can't.
+    fn check_expr(&self, _expr: &Node) -> Option<String> {
+        None
+    }
+}
+
+/// Counting up or down by a fixed step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub start: Num,
+    pub step: Num,
+}
+
+impl Step {
+    /// Counting by `step`s from `step`, so by 2s is 2, 4, 6...
+    pub fn by(step: i64) -> Step {
+        Step { start: to_num(step), step: to_num(step) }
+    }
+
+    /// Counting down by ones from `from`.
+    pub fn countdown(from: i64) -> Step {
+        Step { start: to_num(from), step: to_num(-1) }
+    }
+}
+
+impl Default for Step {
+    fn default() -> Step {
+        Step::by(1)
+    }
+}
+
+impl CountingMode for Step {
+    fn origin(&self) -> Num {
+        &self.start - &self.step
+    }
+
+    fn next(&self, prev: &Num) -> Num {
+        prev + &self.step
+    }
+}
+
+/// Counting up by ones, with every number written in one base.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BaseN {
+    radix: u32,
+}
+
+impl BaseN {
+    /// `radix` must be one the lexer reads: 2, 8, 10 or 16.
+    pub fn new(radix: u32) -> Result<BaseN> {
+        match radix {
+            2 | 8 | 10 | 16 => Ok(BaseN { radix }),
+            _ => Err(simple_error!("Numbers can't be written in base {}", radix))?,
+        }
+    }
+
+    pub fn radix(&self) -> u32 {
+        self.radix
+    }
+
+    fn base_name(&self) -> &'static str {
+        match self.radix {
+            2 => "binary",
+            8 => "octal",
+            16 => "hex",
+            _ => "decimal",
+        }
+    }
+}
+
+impl CountingMode for BaseN {
+    fn origin(&self) -> Num {
+        to_num(0)
+    }
+
+    fn next(&self, prev: &Num) -> Num {
+        prev + to_num(1)
+    }
+
+    fn check_expr(&self, expr: &Node) -> Option<String> {
+        match expr {
+            Number(_, source) if source.radix() != self.radix =>
+                Some(format!("Numbers must be written in {}", self.base_name())),
+            Number(_, _) | Roll(_, _) | Var(_) => None,
+            UnaOp(_, e) | BadParse(e) => self.check_expr(e),
+            BinOp(_, l, r) => self.check_expr(l).or_else(|| self.check_expr(r)),
+            Funcall(_, args) => args.iter().find_map(|e| self.check_expr(e)),
+        }
+    }
+}
+
+// The sequences below only hold integers, so anything between two of
+// them is treated as the lower
+fn floor_int(n: &Num) -> BigInt {
+    n.floor().to_integer()
+}
+
+/// Counting the primes: 2, 3, 5, 7...
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Primes;
+
+impl CountingMode for Primes {
+    fn origin(&self) -> Num {
+        to_num(1)
+    }
+
+    fn next(&self, prev: &Num) -> Num {
+        let mut n = floor_int(prev) + 1;
+        while !funcs::is_prime(&n) {
+            n += 1;
+        }
+        Num::from_integer(n)
+    }
+}
+
+/// Counting the Fibonacci numbers, without repeating 1: 1, 2, 3, 5...
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Fibonacci;
+
+impl CountingMode for Fibonacci {
+    fn origin(&self) -> Num {
+        to_num(0)
+    }
+
+    fn next(&self, prev: &Num) -> Num {
+        let prev = floor_int(prev);
+        let (mut a, mut b) = (BigInt::one(), BigInt::from(2));
+        while a <= prev {
+            let next = &a + &b;
+            a = std::mem::replace(&mut b, next);
+        }
+        Num::from_integer(a)
+    }
+}
+
+/// Counting the perfect squares: 1, 4, 9, 16...
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Squares;
+
+impl CountingMode for Squares {
+    fn origin(&self) -> Num {
+        to_num(0)
+    }
+
+    fn next(&self, prev: &Num) -> Num {
+        let prev = floor_int(prev);
+        let root: BigInt = if prev.is_negative() { BigInt::from(-1) } else { prev.sqrt() } + 1;
+        Num::from_integer(root.pow(2))
+    }
+}
+
+/// A description of one of the modes above, for choosing one from
+/// outside Rust.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ModeSpec {
+    Step { start: i64, step: i64 },
+    Countdown { from: i64 },
+    Base { radix: u32 },
+    Primes,
+    Fibonacci,
+    Squares,
+}
+
+impl Default for ModeSpec {
+    fn default() -> ModeSpec {
+        ModeSpec::Step { start: 1, step: 1 }
+    }
+}
+
+impl ModeSpec {
+    pub fn build(&self) -> Result<Arc<dyn CountingMode>> {
+        Ok(match *self {
+            ModeSpec::Step { start, step } => Arc::new(Step { start: to_num(start), step: to_num(step) }),
+            ModeSpec::Countdown { from } => Arc::new(Step::countdown(from)),
+            ModeSpec::Base { radix } => Arc::new(BaseN::new(radix)?),
+            ModeSpec::Primes => Arc::new(Primes),
+            ModeSpec::Fibonacci => Arc::new(Fibonacci),
+            ModeSpec::Squares => Arc::new(Squares),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::best_parse;
+
+    fn first(mode: &dyn CountingMode, n: usize) -> Vec<Num> {
+        let mut count = mode.origin();
+        (0..n).map(|_| {
+            count = mode.next(&count);
+            count.clone()
+        }).collect()
+    }
+
+    fn nums(is: &[i64]) -> Vec<Num> {
+        is.iter().map(|i| to_num(*i)).collect()
+    }
+
+    #[test]
+    fn test_sequences() {
+        assert_eq!(first(&Step::default(), 4), nums(&[1, 2, 3, 4]));
+        assert_eq!(first(&Step::by(7), 3), nums(&[7, 14, 21]));
+        assert_eq!(first(&Step::countdown(1000), 3), nums(&[1000, 999, 998]));
+        assert_eq!(first(&BaseN::new(16).unwrap(), 3), nums(&[1, 2, 3]));
+        assert_eq!(first(&Primes, 6), nums(&[2, 3, 5, 7, 11, 13]));
+        assert_eq!(first(&Fibonacci, 6), nums(&[1, 2, 3, 5, 8, 13]));
+        assert_eq!(first(&Squares, 4), nums(&[1, 4, 9, 16]));
+
+        assert!(Primes.accepts(&to_num(13), &to_num(17)));
+        assert!(!Primes.accepts(&to_num(13), &to_num(15)));
+    }
+
+    #[test]
+    fn test_base_n() {
+        assert!(BaseN::new(3).is_err());
+
+        let hex = BaseN::new(16).unwrap();
+        assert_eq!(hex.check_expr(&best_parse("0x1f + 0x1").unwrap()), None);
+        assert_eq!(hex.check_expr(&best_parse("0x1f + 1").unwrap()),
+                   Some("Numbers must be written in hex".to_string()));
+        assert!(hex.check_expr(&best_parse("abs(0b11)").unwrap()).is_some());
+
+        let decimal = BaseN::new(10).unwrap();
+        assert_eq!(decimal.check_expr(&best_parse("one + 1").unwrap()), None);
+        assert!(decimal.check_expr(&best_parse("0o7").unwrap()).is_some());
+    }
+
+    #[test]
+    fn test_mode_spec() {
+        let spec: ModeSpec = serde_json::from_str(r#"{"type": "countdown", "from": 10}"#).unwrap();
+        assert_eq!(first(&*spec.build().unwrap(), 2), nums(&[10, 9]));
+
+        let spec: ModeSpec = serde_json::from_str(r#"{"type": "base", "radix": 3}"#).unwrap();
+        assert!(spec.build().is_err());
+    }
+}
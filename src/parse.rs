@@ -1,76 +1,195 @@
 use crate::grammar;
 use crate::util;
 use crate::ast::*;
+use crate::rank::{self, ParseRanker};
+use serde::Serialize;
+use std::ops::Range;
 
 type ParseResult<'a> =
     Result<Expr, lalrpop_util::ParseError<usize, util::Token<'a>, String>>;
 
 fn good_parse<'a>(r: &ParseResult<'a>) -> bool {
     match r {
-        Ok(expr) => match **expr {
-            BadParse(_) => false,
-            _ => true,
-        }
+        Ok(expr) => !matches!(expr.node, BadParse(_)),
         Err(_) => false,
     }
 }
 
+// Parses `tokens`, lexed from `line`. If they don't parse as lexed,
+// tries again reading each "-" stuck to a number as its sign, for
+// postfix and prefix lines like "3 -2 *".
+fn parse_tokens<'a>(line: &'a str, tokens: &[util::SpannedToken<'a>]) -> ParseResult<'a> {
+    let parser = grammar::TopLevelParser::new();
+    let parse = parser.parse(line, tokens.iter().cloned());
+    if good_parse(&parse) {
+        return parse;
+    }
+
+    let signed = util::with_signs(line, tokens);
+    if signed == tokens {
+        return parse;
+    }
+    match parser.parse(line, signed) {
+        signed if good_parse(&signed) => signed,
+        _ => parse,
+    }
+}
+
 // An expression's size in number of nodes. This is one possibility
 // for choosing the best parse of a string. Another would be its
-// length in characters.
-//
-// I think I can capture the span of an expression just at the
-// top-level parse in lalrpop, so I'd wrap the top-level expression in
-// a "spanned" type.
-fn expr_size(e: &Node) -> i32 {
+// length in characters, which its span now gives us.
+pub fn expr_size(e: &Node) -> i32 {
     match e {
         Number(_, _) => 1,
         Roll(_, _) => 2,
         Var(_) => 1,
-        UnaOp(_, e) => 1 + expr_size(&e),
-        BinOp(_, l, r) => 1 + expr_size(&l) + expr_size(&r),
-        Funcall(_, exprs) => 1 + exprs.iter().map(|e| { expr_size(&e) }).sum::<i32>(),
-        BadParse(e) => expr_size(&e),
+        UnaOp(_, e) => 1 + expr_size(e),
+        BinOp(_, l, r) => 1 + expr_size(l) + expr_size(r),
+        Funcall(_, exprs) => 1 + exprs.iter().map(|e| { expr_size(e) }).sum::<i32>(),
+        BadParse(e) => expr_size(e),
+    }
+}
+
+/// One successful parse of some suffix of a line.
+#[derive(Debug, Serialize)]
+pub struct Candidate {
+    pub expr: Expr,
+    /// The tokens of the line the expression was parsed from
+    pub tokens: Range<usize>,
+    /// The expression's `expr_size`
+    pub size: i32,
+    /// Whether the parse stopped at an error, leaving trailing junk
+    pub recovered: bool,
+}
+
+impl Candidate {
+    /// A parse of the whole line wins outright
+    pub fn is_whole_line(&self) -> bool {
+        self.tokens.start == 0 && !self.recovered
     }
 }
 
+/// Every parse of every suffix of `line`, best first: a parse of the
+/// whole line, then by decreasing size, with ties going to the
+/// earlier parse.
+pub fn all_parses(line: &str) -> Vec<Candidate> {
+    all_parses_with(line, &rank::BySize)
+}
+
+/// Like `all_parses`, but ordered by `ranker`.
+pub fn all_parses_with(line: &str, ranker: &dyn ParseRanker) -> Vec<Candidate> {
+    let mut candidates = suffix_parses(line);
+
+    // Both sorts are stable, so candidates the ranker can't split keep
+    // their order, and a whole-line parse moves ahead of the rest
+    ranker.sort(&mut candidates);
+    candidates.sort_by_key(|c| !c.is_whole_line());
+    candidates
+}
+
+/// Every expression embedded in `line`, in order. Each is the
+/// longest parse from where it starts, none overlap, and lone names
+/// are skipped since they're usually just words.
+pub fn find_exprs(line: &str) -> Vec<Candidate> {
+    let mut found = Vec::new();
+    let mut next = 0;
+
+    for c in maximal_parses(line) {
+        if c.tokens.start >= next {
+            next = c.tokens.end;
+            found.push(c);
+        }
+    }
+    found
+}
+
+/// The parses of `line` that aren't contained in any other, by start
+/// position. Unlike `find_exprs`, these may overlap.
+pub fn maximal_parses(line: &str) -> Vec<Candidate> {
+    let mut furthest = 0;
+
+    // Each start has at most one parse, so only an earlier one that
+    // reaches at least as far can contain it
+    suffix_parses(line).into_iter()
+        .filter(|c| !matches!(c.expr.node, Var(_)))
+        .filter(|c| {
+            let maximal = c.tokens.end > furthest;
+            furthest = std::cmp::max(furthest, c.tokens.end);
+            maximal
+        })
+        .collect()
+}
+
+// The parse from each token of `line` that has one, in order
+fn suffix_parses(line: &str) -> Vec<Candidate> {
+    let lexer = util::TokenLexer::new(line);
+    let tokens: Vec<_> = lexer.collect();
+
+    (0..tokens.len())
+        .filter_map(|i| {
+            let expr = parse_tokens(line, &tokens[i..]).ok()?;
+            Some(candidate(expr, i, &tokens))
+        })
+        .collect()
+}
+
+fn candidate<'a>(expr: Expr, start: usize, tokens: &[util::SpannedToken<'a>]) -> Candidate {
+    let (expr, recovered) = match expr.node {
+        BadParse(e) => (e, true),
+        _ => (expr, false),
+    };
+    let end = start + tokens[start..].iter()
+        .take_while(|t| matches!(t, Ok((_, _, e)) if *e <= expr.span.end))
+        .count();
+    let size = expr_size(&expr);
+
+    Candidate { expr, tokens: start..end, size, recovered }
+}
+
+/// Finds the most plausible expression in `line`. Its `span` (and
+/// those of its subexpressions) index into `line`.
 pub fn best_parse(line: &str) -> Option<Expr> {
+    best_parse_with(line, &rank::BySize)
+}
+
+/// Like `best_parse`, but choosing between partial parses with
+/// `ranker`.
+pub fn best_parse_with(line: &str, ranker: &dyn ParseRanker) -> Option<Expr> {
+    best_candidate_with(line, ranker).map(|c| c.expr)
+}
+
+/// Like `best_parse_with`, but also saying where in `line` the
+/// expression came from and whether junk followed it.
+pub fn best_candidate_with(line: &str, ranker: &dyn ParseRanker) -> Option<Candidate> {
+    best_candidate_counted(line, ranker).0
+}
+
+/// Like `best_candidate_with`, but also saying how many candidates the
+/// best was chosen from: one if the whole line parsed.
+pub fn best_candidate_counted(line: &str, ranker: &dyn ParseRanker) -> (Option<Candidate>, usize) {
     let lexer = util::TokenLexer::new(line);
     let tokens: Vec<_> = lexer.collect();
-    let parser = grammar::TopLevelParser::new();
 
-    let parse1 = parser.parse(line, tokens.iter().cloned());
+    let parse1 = parse_tokens(line, &tokens);
 
     if good_parse(&parse1) {
-        return Some(parse1.unwrap());
+        return (Some(candidate(parse1.unwrap(), 0, &tokens)), 1);
     }
 
-    // Iterator<Item=Expr>
-    let parses = std::iter::once(parse1).chain(
-        (1..tokens.len()).map(|i| {
-            parser.parse(line, tokens[i..].iter().cloned())
-        })
-    )
-        .filter_map(|p| p.ok())
-        .map(|p| match *p {
-            BadParse(e) => e,
-            _ => p,
-        });
-
-    parses
-        .map(|p| {
-            let size = expr_size(&p);
-            (p, size)
-        })
-        .filter(|(_parse, size)| *size > 1)
-        .reduce(|(parse1, size1), (parse2, size2)| {
-            if size1 >= size2 {
-                (parse1, size1)
-            } else {
-                (parse2, size2)
-            }
-        })
-        .map(|(parse, _size)| parse)
+    let candidates = all_parses_with(line, ranker);
+    let count = candidates.len();
+    (candidates.into_iter().find(could_be_best), count)
+}
+
+/// The candidate `best_candidate_with` would choose, given every
+/// candidate as ordered by `all_parses_with`.
+pub fn best_of(candidates: &[Candidate]) -> Option<&Candidate> {
+    candidates.iter().find(|c| could_be_best(c))
+}
+
+// A parse of part of the line needs more than a lone number or name
+fn could_be_best(c: &Candidate) -> bool {
+    c.is_whole_line() || c.size > 1
 }
 
 #[cfg(test)]
@@ -96,6 +215,82 @@ mod tests {
         println!("Best parse for {:?} is {:?}", string, best_parse(string));
     }
 
+    #[test]
+    fn test_best_parse_funcall() {
+        let parse = best_parse("next is fib(12), I think").unwrap();
+        assert!(parse.same_shape(&Funcall("fib".to_string(), vec![expr(Number(to_num(12), Digits))])),
+                "{:?}", parse);
+    }
+
+    #[test]
+    fn test_spans() {
+        let line = "so (one + 2) * 3! is it";
+        let expr = best_parse(line).unwrap();
+        assert_eq!(&line[expr.span.clone()], "(one + 2) * 3!");
+
+        if let BinOp(Mul, l, r) = &expr.node {
+            assert_eq!(&line[l.span.clone()], "(one + 2)");
+            assert_eq!(&line[r.span.clone()], "3!");
+            if let BinOp(Add, one, two) = &l.node {
+                assert_eq!(&line[one.span.clone()], "one");
+                assert_eq!(&line[two.span.clone()], "2");
+            } else {
+                panic!("Wrong parse {:?}", l);
+            }
+        } else {
+            panic!("Wrong parse {:?}", expr);
+        }
+    }
+
+    #[test]
+    fn test_all_parses() {
+        let line = "ok 1 + 2 * 3 then 4 !";
+        let parses = all_parses(line);
+
+        for c in parses.iter() {
+            println!("{:?} {:?} {} {}", &line[c.expr.span.clone()], c.tokens, c.size, c.recovered);
+        }
+
+        let best = &parses[0];
+        assert_eq!(&line[best.expr.span.clone()], "1 + 2 * 3");
+        assert_eq!(best.tokens, 1..6);
+        assert_eq!(best.size, 5);
+        assert!(best.recovered);
+
+        assert!(parses.windows(2).all(|w| w[0].size >= w[1].size));
+        assert!(parses.iter().any(|c| !c.recovered && &line[c.expr.span.clone()] == "4 !"));
+        assert!(all_parses("").is_empty());
+
+        for line in [line, "42", "so 7 then", "2 + 2"] {
+            assert_eq!(best_of(&all_parses(line)).map(|c| c.tokens.clone()),
+                       best_candidate_with(line, &rank::BySize).map(|c| c.tokens), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_find_exprs() {
+        let cases: &[(&str, &[&str])] = &[
+            ("ok so 12*3 is next, right 5?", &["12*3", "5"]),
+            ("twenty one", &["twenty one"]),
+            ("(1 + 2 then 3", &["1 + 2", "3"]),
+            ("nothing to see here", &[]),
+        ];
+
+        for (line, want) in cases.iter() {
+            let found: Vec<_> = find_exprs(line).iter()
+                .map(|c| &line[c.expr.span.clone()])
+                .collect();
+            assert_eq!(found, *want, "{:?}", line);
+        }
+
+        let line = "2 3 + 4";
+        let maximal: Vec<_> = maximal_parses(line).iter()
+            .map(|c| c.tokens.clone())
+            .collect();
+        println!("{:?}", maximal);
+        assert!(maximal.windows(2).all(|w| w[0].start < w[1].start && w[0].end < w[1].end));
+    }
+
     #[test]
     fn test_unknown() {
         let string = "1 2 + `";
@@ -104,9 +299,11 @@ mod tests {
         let mut lexer = util::TokenLexer::new(string);
         let parse = parser.parse(string, &mut lexer);
 
-        assert_eq!(parse, Ok(Box::new(BadParse(
-            Box::new(BinOp(Add,
-                           Box::new(Number(to_num(1), Digits)),
-                           Box::new(Number(to_num(2), Digits))))))));
+        let parse = parse.unwrap();
+        assert!(parse.same_shape(&BadParse(
+            expr(BinOp(Add,
+                       expr(Number(to_num(1), Digits)),
+                       expr(Number(to_num(2), Digits)))))),
+                "{:?}", parse);
     }
 }
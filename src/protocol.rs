@@ -0,0 +1,223 @@
+use crate::eval::{self, EvalLimits};
+use crate::funcs;
+use crate::parse;
+use crate::rank::Ranking;
+use crate::types::{Request, Response, Result};
+use std::future::Future;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Longest request `respond` will read, in bytes. Longer lines are
+/// answered with an error; a longer JSON request ends the session,
+/// since there's no telling where the next one starts.
+pub const MAX_REQUEST: usize = 65_536;
+
+/// How a client talks to `respond`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// One expression per line in, one result per line out
+    #[default]
+    Lines,
+    /// A stream of `Request`s in, a `Response` per line out
+    Json,
+}
+
+/// The settings one session evaluates with.
+#[derive(Debug, Clone, Default)]
+pub struct Evaluator {
+    pub ranking: Ranking,
+    pub limits: EvalLimits,
+}
+
+impl Evaluator {
+    /// Evaluates the best parse of `line`, newline-terminated.
+    pub fn eval_line(&self, line: &str) -> Result<String> {
+        let ranker = self.ranking.ranker_with(&funcs::BUILTINS, &self.limits);
+        let expr = parse::best_parse_with(line, &*ranker).ok_or_else(
+            || simple_error!("No good parses in '{}'", line))?;
+
+        eval::eval_with(&expr, &Default::default(), &funcs::BUILTINS, &self.limits)
+            .map(|v| format!("{}\n", v))
+    }
+
+    // Evaluating may take up to the timeout, so it's kept off the async
+    // workers
+    async fn eval_blocking(&self, line: String) -> Result<std::result::Result<String, String>> {
+        let evaluator = self.clone();
+        Ok(tokio::task::spawn_blocking(move || evaluator.eval_line(&line).map_err(|e| e.to_string())).await?)
+    }
+}
+
+/// Answers everything read from `reader` until it ends or `shutdown`
+/// resolves. A request already read is still answered on shutdown.
+pub async fn respond<R, W, F>(protocol: Protocol, reader: R, writer: W, evaluator: &Evaluator,
+                              shutdown: F) -> Result<()>
+    where R: AsyncRead + Unpin,
+          W: AsyncWrite + Unpin,
+          F: Future<Output = ()>,
+{
+    match protocol {
+        Protocol::Lines => respond_lines(reader, writer, evaluator, shutdown).await,
+        Protocol::Json => respond_json(reader, writer, evaluator, shutdown).await,
+    }
+}
+
+async fn respond_lines<R, W, F>(reader: R, mut writer: W, evaluator: &Evaluator,
+                                shutdown: F) -> Result<()>
+    where R: AsyncRead + Unpin,
+          W: AsyncWrite + Unpin,
+          F: Future<Output = ()>,
+{
+    let mut breader = BufReader::new(reader);
+    let mut linebuf = Vec::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        linebuf.clear();
+        let mut limited = (&mut breader).take(MAX_REQUEST as u64 + 1);
+        let n = tokio::select! {
+            n = limited.read_until(b'\n', &mut linebuf) => n?,
+            _ = &mut shutdown => 0,
+        };
+        if n == 0 {
+            return Ok(());
+        }
+
+        let msg = if linebuf.len() > MAX_REQUEST && !linebuf.ends_with(b"\n") {
+            tokio::select! {
+                res = skip_line(&mut breader) => res?,
+                _ = &mut shutdown => return Ok(()),
+            }
+            format!("Requests can be at most {} bytes\n", MAX_REQUEST)
+        } else {
+            let line = std::str::from_utf8(&linebuf)?.to_string();
+            match evaluator.eval_blocking(line).await? {
+                Ok(res) => res,
+                Err(e) => format!("{}\n", e),
+            }
+        };
+        writer.write_all(msg.as_bytes()).await?;
+        writer.flush().await?;
+    }
+}
+
+// Reads up to and including the next newline, keeping none of it
+async fn skip_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<()> {
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|b| *b == b'\n') {
+            Some(i) => {
+                reader.consume(i + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+async fn respond_json<R, W, F>(mut reader: R, mut writer: W, evaluator: &Evaluator,
+                               shutdown: F) -> Result<()>
+    where R: AsyncRead + Unpin,
+          W: AsyncWrite + Unpin,
+          F: Future<Output = ()>,
+{
+    // Requests needn't be one per line, so read until the buffer holds
+    // at least one whole one
+    let mut buf = Vec::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let mut requests = serde_json::Deserializer::from_slice(&buf).into_iter::<Request>();
+        let mut read = Vec::new();
+        let mut too_large = false;
+        loop {
+            let start = requests.byte_offset();
+            match requests.next() {
+                Some(Ok(_)) if requests.byte_offset() - start > MAX_REQUEST => {
+                    too_large = true;
+                    break;
+                }
+                Some(Ok(req)) => read.push(req),
+                Some(Err(e)) if e.is_eof() => break,
+                Some(Err(e)) => Err(e)?,
+                None => break,
+            }
+        }
+        let used = requests.byte_offset();
+        buf.drain(..used);
+        // What's left is the start of a request still to come
+        too_large |= buf.len() > MAX_REQUEST;
+
+        let mut answered = Vec::new();
+        for req in read {
+            answered.push(match evaluator.eval_blocking(req.message).await? {
+                Ok(str) => Response::Good { val: Some(str) },
+                Err(message) => Response::Bad { message, code: None },
+            });
+        }
+        if too_large {
+            answered.push(Response::Bad {
+                message: format!("Requests can be at most {} bytes", MAX_REQUEST),
+                code: Some("request_too_large".to_string()),
+            });
+        }
+
+        for res in answered {
+            let mut line = serde_json::to_vec(&res)?;
+            line.push(b'\n');
+            writer.write_all(&line).await?;
+        }
+        writer.flush().await?;
+        if too_large {
+            return Ok(());
+        }
+
+        let mut chunk = [0; 4096];
+        let n = tokio::select! {
+            n = reader.read(&mut chunk) => n?,
+            _ = &mut shutdown => 0,
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn run(protocol: Protocol, input: &str) -> String {
+        let mut out = Vec::new();
+        respond(protocol, input.as_bytes(), &mut out, &Default::default(),
+                std::future::pending()).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_protocols() {
+        assert_eq!(run(Protocol::Lines, "1 + 1\nhmm\nsix\n").await,
+                   "2\nUnbound variable \"hmm\"\n6\n");
+
+        let out = run(Protocol::Json, r#"{"message": "2 * 3"} {"message": "x"}
+                                         {"message": "4"}"#).await;
+        let responses: Vec<Response> = out.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert!(matches!(&responses[..], [Response::Good { val: Some(a) }, Response::Bad { .. },
+                                          Response::Good { val: Some(b) }] if a == "6\n" && b == "4\n"));
+
+        // Too long a line is skipped, and too long a request ends things
+        let long = "1".repeat(MAX_REQUEST + 1);
+        assert_eq!(run(Protocol::Lines, &format!("{}\n2\n", long)).await,
+                   format!("Requests can be at most {} bytes\n2\n", MAX_REQUEST));
+        let out = run(Protocol::Json, &format!(r#"{{"message": "2"}} {{"message": "{}"}} {{"message": "3"}}"#, long)).await;
+        let responses: Vec<Response> = out.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert!(matches!(&responses[..], [Response::Good { .. }, Response::Bad { code: Some(code), .. }]
+                         if code == "request_too_large"));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::ast::*;
+use crate::eval::{self, EvalLimits};
+use crate::funcs::{self, FunctionRegistry};
+use crate::parse::Candidate;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+
+/// Decides which of a line's candidate parses was most likely meant.
+/// A parse of the whole line always comes first; rankers only order
+/// the rest.
+pub trait ParseRanker {
+    /// Orders two candidates, best first. Candidates that compare
+    /// equal keep their order in the line.
+    fn compare(&self, a: &Candidate, b: &Candidate) -> Ordering;
+
+    /// Orders `candidates` best first, keeping the order of those that
+    /// compare equal. Worth overriding when `compare` is costly.
+    fn sort(&self, candidates: &mut [Candidate]) {
+        candidates.sort_by(|a, b| self.compare(a, b));
+    }
+}
+
+/// The largest expression by `expr_size`. This is the default.
+pub struct BySize;
+
+impl ParseRanker for BySize {
+    fn compare(&self, a: &Candidate, b: &Candidate) -> Ordering {
+        b.size.cmp(&a.size)
+    }
+}
+
+/// The expression covering the most characters of the line.
+pub struct BySpan;
+
+impl ParseRanker for BySpan {
+    fn compare(&self, a: &Candidate, b: &Candidate) -> Ordering {
+        b.expr.span.len().cmp(&a.expr.span.len())
+    }
+}
+
+/// The expression starting earliest in the line.
+pub struct ByStart;
+
+impl ParseRanker for ByStart {
+    fn compare(&self, a: &Candidate, b: &Candidate) -> Ordering {
+        a.tokens.start.cmp(&b.tokens.start)
+    }
+}
+
+/// The expression with the fewest spelled-out numbers, then by size.
+pub struct PreferDigits;
+
+impl ParseRanker for PreferDigits {
+    fn compare(&self, a: &Candidate, b: &Candidate) -> Ordering {
+        word_count(&a.expr).cmp(&word_count(&b.expr))
+            .then_with(|| BySize.compare(a, b))
+    }
+}
+
+fn word_count(e: &Node) -> usize {
+    match e {
+        Number(_, Words) => 1,
+        Number(_, _) | Roll(_, _) | Var(_) => 0,
+        UnaOp(_, e) | BadParse(e) => word_count(e),
+        BinOp(_, l, r) => word_count(l) + word_count(r),
+        Funcall(_, args) => args.iter().map(|e| word_count(e)).sum(),
+    }
+}
+
+/// Expressions that evaluate without error, then by size.
+pub struct PreferEvaluable<'a> {
+    pub funcs: &'a FunctionRegistry,
+    pub limits: EvalLimits,
+}
+
+impl PreferEvaluable<'_> {
+    fn evaluates(&self, c: &Candidate) -> bool {
+        eval::eval_with(&c.expr, &Default::default(), self.funcs, &self.limits).is_ok()
+    }
+}
+
+impl ParseRanker for PreferEvaluable<'_> {
+    fn compare(&self, a: &Candidate, b: &Candidate) -> Ordering {
+        self.evaluates(b).cmp(&self.evaluates(a)).then_with(|| BySize.compare(a, b))
+    }
+
+    // Each evaluation may take up to the timeout, and rolls may not
+    // come out the same twice, so evaluate each candidate just once
+    fn sort(&self, candidates: &mut [Candidate]) {
+        candidates.sort_by_cached_key(|c| (!self.evaluates(c), Reverse(c.size)));
+    }
+}
+
+/// The built-in rankers, for picking one by name.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Ranking {
+    #[default]
+    Size,
+    Span,
+    Start,
+    Digits,
+    Evaluates,
+}
+
+impl Ranking {
+    /// The ranker, evaluating with the built-ins and default limits
+    /// if it evaluates at all.
+    pub fn ranker(self) -> Box<dyn ParseRanker> {
+        self.ranker_with(&funcs::BUILTINS, &EvalLimits::default())
+    }
+
+    pub fn ranker_with<'a>(self, funcs: &'a FunctionRegistry, limits: &EvalLimits) -> Box<dyn ParseRanker + 'a> {
+        match self {
+            Ranking::Size => Box::new(BySize),
+            Ranking::Span => Box::new(BySpan),
+            Ranking::Start => Box::new(ByStart),
+            Ranking::Digits => Box::new(PreferDigits),
+            Ranking::Evaluates => Box::new(PreferEvaluable { funcs, limits: limits.clone() }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::best_parse_with;
+
+    #[test]
+    fn test_rankers() {
+        let cases: &[(&str, Ranking, &str)] = &[
+            ("1+2; ((7)) * 100000", Ranking::Size, "1+2"),
+            ("1+2; ((7)) * 100000", Ranking::Span, "((7)) * 100000"),
+            ("d6; 1 + 2 + 3", Ranking::Size, "1 + 2 + 3"),
+            ("d6; 1 + 2 + 3", Ranking::Start, "d6"),
+            ("one plus two; 3 + 4", Ranking::Size, "one plus two"),
+            ("one plus two; 3 + 4", Ranking::Digits, "3 + 4"),
+            ("x * 2; 1 + 3", Ranking::Size, "x * 2"),
+            ("x * 2; 1 + 3", Ranking::Evaluates, "1 + 3"),
+        ];
+
+        for (line, ranking, want) in cases.iter() {
+            let expr = best_parse_with(line, &*ranking.ranker()).unwrap();
+            assert_eq!(&line[expr.span.clone()], *want, "{:?} by {:?}", line, ranking);
+        }
+
+        // Under tighter limits, the big product no longer evaluates
+        let limits = EvalLimits { max_bits: 8, ..Default::default() };
+        let ranker = Ranking::Evaluates.ranker_with(&funcs::BUILTINS, &limits);
+        let line = "1000 * 1000; 1 + 3";
+        let expr = best_parse_with(line, &*ranker).unwrap();
+        assert_eq!(&line[expr.span.clone()], "1 + 3");
+    }
+}
@@ -0,0 +1,259 @@
+use crate::ast::*;
+use crate::parse::{self, Candidate};
+use crate::types;
+use derive_more::Display;
+use num::Signed;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// The kinds of node an expression can be built from.
+#[derive(Serialize, Deserialize, Debug, Display, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum NodeKind {
+    #[display(fmt = "numbers")]
+    Number,
+    #[display(fmt = "dice rolls")]
+    Roll,
+    #[display(fmt = "variables")]
+    Var,
+    #[display(fmt = "operators")]
+    BinOp,
+    #[display(fmt = "negation and factorials")]
+    UnaOp,
+    #[display(fmt = "function calls")]
+    Funcall,
+}
+
+impl NodeKind {
+    /// The kind of `node`. `BadParse` has none, being only a wrapper.
+    pub fn of(node: &Node) -> Option<NodeKind> {
+        match node {
+            Number(_, _) => Some(NodeKind::Number),
+            Roll(_, _) => Some(NodeKind::Roll),
+            Var(_) => Some(NodeKind::Var),
+            BinOp(_, _, _) => Some(NodeKind::BinOp),
+            UnaOp(_, _) => Some(NodeKind::UnaOp),
+            Funcall(_, _) => Some(NodeKind::Funcall),
+            BadParse(_) => None,
+        }
+    }
+}
+
+/// Why a `RuleSet` turned an expression down, worded to be shown to
+/// whoever wrote it.
+#[derive(Debug, Display, PartialEq, Clone)]
+pub enum Violation {
+    #[display(fmt = "No {} allowed", _0)]
+    NodeNotAllowed(NodeKind),
+    #[display(fmt = "The {} operator isn't allowed", "_0.symbol()")]
+    OpNotAllowed(BinOpcode),
+    #[display(fmt = "Numbers can't be written in {}", "source_name(*_0)")]
+    SourceNotAllowed(NumSource),
+    #[display(fmt = "Too simple: needs at least {} parts, has {}", min, size)]
+    TooSmall { size: i32, min: i32 },
+    #[display(fmt = "Too complicated: allows at most {} parts, has {}", max, size)]
+    TooLarge { size: i32, max: i32 },
+    #[display(fmt = "Numbers can have at most {} digits", max)]
+    TooManyDigits { max: usize },
+    #[display(fmt = "Nothing may follow the number")]
+    TrailingJunk,
+}
+
+fn source_name(source: NumSource) -> &'static str {
+    match source {
+        Digits => "decimal",
+        Words => "words",
+        Hex => "hex",
+        Octal => "octal",
+        Binary => "binary",
+    }
+}
+
+/// Restrictions on how an expression may be written, whatever it
+/// evaluates to. `None` means no restriction.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RuleSet {
+    pub allowed_nodes: Option<HashSet<NodeKind>>,
+    pub allowed_ops: Option<HashSet<BinOpcode>>,
+    pub allowed_sources: Option<HashSet<NumSource>>,
+    /// Fewest nodes allowed, by `expr_size`
+    pub min_size: Option<i32>,
+    /// Most nodes allowed, by `expr_size`
+    pub max_size: Option<i32>,
+    /// Most digits any one number may be written with, in its own base,
+    /// not counting leading zeros before the point. Numbers in words
+    /// are measured by the decimal digits of their value.
+    pub max_digits: Option<usize>,
+    /// Whether the expression may be followed by unparseable junk
+    pub allow_bad_parse: bool,
+}
+
+impl Default for RuleSet {
+    fn default() -> RuleSet {
+        RuleSet {
+            allowed_nodes: None,
+            allowed_ops: None,
+            allowed_sources: None,
+            min_size: None,
+            max_size: None,
+            max_digits: None,
+            allow_bad_parse: true,
+        }
+    }
+}
+
+impl RuleSet {
+    /// Reads rules from a JSON file. Rules left out don't apply.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> types::Result<RuleSet> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// The first rule `expr`, parsed from `line`, breaks, if any. A
+    /// `BadParse` anywhere in it counts as trailing junk.
+    pub fn check(&self, expr: &Spanned<Node>, line: &str) -> Result<(), Violation> {
+        let size = parse::expr_size(expr);
+        if let Some(min) = self.min_size {
+            if size < min {
+                return Err(Violation::TooSmall { size, min });
+            }
+        }
+        if let Some(max) = self.max_size {
+            if size > max {
+                return Err(Violation::TooLarge { size, max });
+            }
+        }
+
+        self.check_nodes(expr, line)
+    }
+
+    /// Like `check`, but also catches junk `best_parse` left out of
+    /// the candidate's expression.
+    pub fn check_candidate(&self, candidate: &Candidate, line: &str) -> Result<(), Violation> {
+        if candidate.recovered && !self.allow_bad_parse {
+            return Err(Violation::TrailingJunk);
+        }
+        self.check(&candidate.expr, line)
+    }
+
+    fn check_nodes(&self, expr: &Spanned<Node>, line: &str) -> Result<(), Violation> {
+        if let (Some(kind), Some(allowed)) = (NodeKind::of(expr), &self.allowed_nodes) {
+            if !allowed.contains(&kind) {
+                return Err(Violation::NodeNotAllowed(kind));
+            }
+        }
+
+        match &expr.node {
+            Number(n, source) => {
+                if let Some(allowed) = &self.allowed_sources {
+                    if !allowed.contains(source) {
+                        return Err(Violation::SourceNotAllowed(*source));
+                    }
+                }
+                if let Some(max) = self.max_digits {
+                    let text = line.get(expr.span.clone()).unwrap_or("");
+                    if digits_written(n, *source, text) > max {
+                        return Err(Violation::TooManyDigits { max });
+                    }
+                }
+                Ok(())
+            }
+            Roll(_, _) | Var(_) => Ok(()),
+            BinOp(op, l, r) => {
+                if let Some(allowed) = &self.allowed_ops {
+                    if !allowed.contains(op) {
+                        return Err(Violation::OpNotAllowed(*op));
+                    }
+                }
+                self.check_nodes(l, line)?;
+                self.check_nodes(r, line)
+            }
+            UnaOp(_, e) => self.check_nodes(e, line),
+            Funcall(_, args) => args.iter().try_for_each(|e| self.check_nodes(e, line)),
+            BadParse(e) => {
+                if !self.allow_bad_parse {
+                    return Err(Violation::TrailingJunk);
+                }
+                self.check_nodes(e, line)
+            }
+        }
+    }
+}
+
+// The digits `n` was written with in `text`, which may take in
+// brackets around it
+fn digits_written(n: &Num, source: NumSource, text: &str) -> usize {
+    let radix = source.radix();
+    let text = match source {
+        Words => return std::cmp::max(n.numer().abs().to_str_radix(radix).len(),
+                                      n.denom().to_str_radix(radix).len()),
+        Digits => text.trim_start_matches(|c: char| !c.is_ascii_digit() && c != '.'),
+        Hex => text.split_once("0x").map_or("", |(_, digits)| digits),
+        Octal => text.split_once("0o").map_or("", |(_, digits)| digits),
+        Binary => text.split_once("0b").map_or("", |(_, digits)| digits),
+    };
+
+    text.chars()
+        .take_while(|c| c.is_digit(radix) || *c == '.')
+        .skip_while(|c| *c == '0')
+        .filter(|c| *c != '.')
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::best_candidate_with;
+    use crate::rank;
+
+    fn check(rules: &RuleSet, line: &str) -> Result<(), Violation> {
+        rules.check_candidate(&best_candidate_with(line, &rank::BySize).unwrap(), line)
+    }
+
+    #[test]
+    fn test_rules() {
+        let no_dice = RuleSet {
+            allowed_nodes: Some([NodeKind::Number, NodeKind::BinOp].into_iter().collect()),
+            ..Default::default()
+        };
+        assert_eq!(check(&no_dice, "2 + 3"), Ok(()));
+        assert_eq!(check(&no_dice, "d6 + 3"), Err(Violation::NodeNotAllowed(NodeKind::Roll)));
+
+        let needs_op = RuleSet { min_size: Some(2), max_size: Some(5), ..Default::default() };
+        assert_eq!(check(&needs_op, "4"), Err(Violation::TooSmall { size: 1, min: 2 }));
+        assert_eq!(check(&needs_op, "1 + 1 + 1 + 1"), Err(Violation::TooLarge { size: 7, max: 5 }));
+
+        let no_words = RuleSet {
+            allowed_sources: Some([Digits].into_iter().collect()),
+            allowed_ops: Some([Add, Sub].into_iter().collect()),
+            ..Default::default()
+        };
+        assert_eq!(check(&no_words, "1 + 2 - 3"), Ok(()));
+        assert_eq!(check(&no_words, "one + 2"), Err(Violation::SourceNotAllowed(Words)));
+        assert_eq!(check(&no_words, "1 * 2"), Err(Violation::OpNotAllowed(Mul)));
+
+        let short = RuleSet { max_digits: Some(3), allow_bad_parse: false, ..Default::default() };
+        assert_eq!(check(&short, "999 + 0xfff"), Ok(()));
+        assert_eq!(check(&short, "1000 - 1"), Err(Violation::TooManyDigits { max: 3 }));
+        assert_eq!(check(&short, "1.5"), Ok(()));
+        assert_eq!(check(&short, "0.001 + (0b111)"), Ok(()));
+        assert_eq!(check(&short, "12.34"), Err(Violation::TooManyDigits { max: 3 }));
+        assert_eq!(check(&short, "one thousand"), Err(Violation::TooManyDigits { max: 3 }));
+        assert_eq!(check(&short, "1 + 2 ok?"), Err(Violation::TrailingJunk));
+        assert_eq!(check(&short, "so 1 + 2"), Ok(()));
+
+        let parsed: RuleSet = serde_json::from_str(
+            r#"{"allowedNodes": ["number", "binOp"], "allowedOps": ["add"], "maxDigits": 3}"#).unwrap();
+        assert_eq!(parsed, RuleSet {
+            allowed_nodes: Some([NodeKind::Number, NodeKind::BinOp].into_iter().collect()),
+            allowed_ops: Some([Add].into_iter().collect()),
+            max_digits: Some(3),
+            ..Default::default()
+        });
+
+        assert_eq!(Violation::OpNotAllowed(Mul).to_string(), "The * operator isn't allowed");
+        assert_eq!(Violation::NodeNotAllowed(NodeKind::Roll).to_string(), "No dice rolls allowed");
+    }
+}
@@ -0,0 +1,171 @@
+use crate::ast::*;
+use crate::game::UserId;
+use crate::parse;
+use crate::rank::{self, ParseRanker};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// One user's record in a count.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct UserStats {
+    pub correct: u64,
+    pub failures: u64,
+    /// Numbers counted since the user last failed
+    pub streak: u64,
+    pub longest_streak: u64,
+    /// The `expr_size` of the most complex expression counted
+    pub best_size: i32,
+    /// The text of that expression
+    pub best_expr: Option<String>,
+    /// How many times each operator, by `BinOpcode::symbol`, appeared
+    /// in expressions counted
+    pub ops: BTreeMap<String, u64>,
+}
+
+impl UserStats {
+    /// The operators used most, most used first.
+    pub fn favorite_ops(&self) -> Vec<(&str, u64)> {
+        let mut ops: Vec<_> = self.ops.iter().map(|(op, n)| (op.as_str(), *n)).collect();
+        ops.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+        ops
+    }
+}
+
+/// What a leaderboard ranks users by.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Board {
+    Correct,
+    Failures,
+    Streak,
+    Complexity,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub user: UserId,
+    pub score: u64,
+}
+
+/// Per-user statistics for one count, built up from its messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(transparent)]
+pub struct Stats {
+    pub users: BTreeMap<UserId, UserStats>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Default::default()
+    }
+
+    pub fn get(&self, user: &str) -> Option<&UserStats> {
+        self.users.get(user)
+    }
+
+    /// Records that `user` sent `message`, and whether it counted.
+    pub fn record(&mut self, user: &str, message: &str, accepted: bool) {
+        self.record_with(user, message, accepted, &rank::BySize)
+    }
+
+    /// Like `record`, but choosing between partial parses of `message`
+    /// with `ranker`.
+    pub fn record_with(&mut self, user: &str, message: &str, accepted: bool,
+                       ranker: &dyn ParseRanker) {
+        match parse::best_parse_with(message, ranker) {
+            Some(expr) => self.record_expr(user, message, Some(&expr), accepted),
+            None => self.record_expr(user, message, None, accepted),
+        }
+    }
+
+    /// Like `record`, for when `message` has already been parsed to
+    /// `expr`. Only accepted expressions count towards complexity and
+    /// operators.
+    pub fn record_expr(&mut self, user: &str, message: &str, expr: Option<&Expr>,
+                       accepted: bool) {
+        let stats = self.users.entry(user.to_string()).or_default();
+
+        if !accepted {
+            stats.failures += 1;
+            stats.streak = 0;
+            return;
+        }
+
+        stats.correct += 1;
+        stats.streak += 1;
+        stats.longest_streak = std::cmp::max(stats.longest_streak, stats.streak);
+
+        if let Some(expr) = expr {
+            let size = parse::expr_size(expr);
+            if size > stats.best_size {
+                stats.best_size = size;
+                stats.best_expr = message.get(expr.span.clone()).map(String::from);
+            }
+            count_ops(expr, &mut stats.ops);
+        }
+    }
+
+    /// Everyone with a score on `board`, best first, with ties broken
+    /// by name.
+    pub fn leaderboard(&self, board: Board) -> Vec<Entry> {
+        let mut entries: Vec<_> = self.users.iter()
+            .map(|(user, s)| Entry {
+                user: user.clone(),
+                score: match board {
+                    Board::Correct => s.correct,
+                    Board::Failures => s.failures,
+                    Board::Streak => s.longest_streak,
+                    Board::Complexity => s.best_size as u64,
+                },
+            })
+            .filter(|e| e.score > 0)
+            .collect();
+        entries.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.user.cmp(&b.user)));
+        entries
+    }
+}
+
+/// Adds one to `ops[symbol]` for each operator in `e`.
+pub(crate) fn count_ops(e: &Node, ops: &mut BTreeMap<String, u64>) {
+    match e {
+        Number(_, _) | Roll(_, _) | Var(_) => (),
+        BinOp(op, l, r) => {
+            *ops.entry(op.symbol().to_string()).or_default() += 1;
+            count_ops(l, ops);
+            count_ops(r, ops);
+        }
+        UnaOp(_, e) | BadParse(e) => count_ops(e, ops),
+        Funcall(_, args) => args.iter().for_each(|e| count_ops(e, ops)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::new();
+        for (user, msg, ok) in [("a", "1", true), ("b", "1 + 1", true), ("a", "so 1 + 2 * (2 - 2)", true),
+                                ("b", "5", false), ("a", "2 + 2", true), ("c", "oops", false),
+                                ("b", "5 * 1", true)] {
+            stats.record(user, msg, ok);
+        }
+
+        let a = stats.get("a").unwrap();
+        assert_eq!((a.correct, a.failures, a.longest_streak), (3, 0, 3));
+        assert_eq!(a.best_expr.as_deref(), Some("1 + 2 * (2 - 2)"));
+        assert_eq!(a.favorite_ops(), vec![("+", 2), ("*", 1), ("-", 1)]);
+
+        let b = stats.get("b").unwrap();
+        assert_eq!((b.correct, b.failures, b.streak, b.longest_streak), (2, 1, 1, 1));
+
+        let users = |board| -> Vec<_> {
+            stats.leaderboard(board).into_iter().map(|e| (e.user, e.score)).collect()
+        };
+        assert_eq!(users(Board::Correct), vec![("a".to_string(), 3), ("b".to_string(), 2)]);
+        assert_eq!(users(Board::Failures), vec![("b".to_string(), 1), ("c".to_string(), 1)]);
+        assert_eq!(users(Board::Complexity)[0], ("a".to_string(), 7));
+    }
+}
@@ -0,0 +1,388 @@
+use crate::ast::Num;
+use crate::game::GameState;
+use crate::stats::{Stats, UserStats};
+use crate::types::Result;
+use num::ToPrimitive;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The layout version written by every store. Stores refuse to open
+/// anything newer; migrations from older layouts belong in `open`.
+///
+/// 2 added run lengths, worked out from version 1's counts.
+/// 3 added streaks, best expressions and operator counts to user stats.
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// Somewhere to keep counting state between runs, keyed by session
+/// (a channel, say).
+pub trait Store: Send + Sync {
+    /// The saved state of `session`, if it's been saved.
+    fn load(&self, session: &str) -> Result<Option<GameState>>;
+
+    /// Replaces the saved state of `session`. Either all of `state` is
+    /// saved or none of it is.
+    fn save(&self, session: &str, state: &GameState) -> Result<()>;
+}
+
+/// Keeps nothing past the life of the process.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, GameState>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        Default::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn load(&self, session: &str) -> Result<Option<GameState>> {
+        Ok(self.sessions.lock().unwrap().get(session).cloned())
+    }
+
+    fn save(&self, session: &str, state: &GameState) -> Result<()> {
+        self.sessions.lock().unwrap().insert(session.to_string(), state.clone());
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct JsonFile {
+    version: u32,
+    sessions: BTreeMap<String, GameState>,
+}
+
+/// Keeps every session in one JSON file. Saves write a new file
+/// beside it and rename it into place, so a crash can't leave the file
+/// half written.
+pub struct JsonStore {
+    path: PathBuf,
+    // Held across load-modify-write, so concurrent saves don't drop
+    // each other's sessions
+    lock: Mutex<()>,
+}
+
+impl JsonStore {
+    /// A store at `path`, which needn't exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<JsonStore> {
+        let store = JsonStore { path: path.as_ref().to_path_buf(), lock: Mutex::new(()) };
+        store.read()?;
+        Ok(store)
+    }
+
+    fn read(&self) -> Result<JsonFile> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(JsonFile { version: SCHEMA_VERSION, ..Default::default() });
+            }
+            Err(e) => Err(e)?,
+        };
+
+        let mut file: JsonFile = serde_json::from_str(&text)?;
+        if file.version == 1 {
+            file.sessions.values_mut().for_each(runs_from_v1);
+        }
+        // Otherwise older versions only lack fields that default
+        match file.version {
+            1..=SCHEMA_VERSION => Ok(file),
+            v => Err(simple_error!("{} has schema version {}, but only {} is supported",
+                                   self.path.display(), v, SCHEMA_VERSION))?,
+        }
+    }
+
+    fn write(&self, file: &JsonFile) -> Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(&mut tmp, file)?;
+        tmp.write_all(b"\n")?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Store for JsonStore {
+    fn load(&self, session: &str) -> Result<Option<GameState>> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(self.read()?.sessions.remove(session))
+    }
+
+    fn save(&self, session: &str, state: &GameState) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let mut file = self.read()?;
+        // Whatever version was read has been migrated by now
+        file.version = SCHEMA_VERSION;
+        file.sessions.insert(session.to_string(), state.clone());
+        self.write(&file)
+    }
+}
+
+// Version 1 only counted up by one, so a count stands in for the run
+// that reached it. Both are off by the same start, so a run beats the
+// best run exactly when its count would have beaten the high score.
+fn runs_from_v1(state: &mut GameState) {
+    let run = |n: &Num| n.to_integer().to_u64().unwrap_or(0);
+    state.run = run(&state.count);
+    state.best_run = run(&state.high_score);
+}
+
+/// Keeps sessions in an SQLite database, one transaction per save.
+/// The schema version is the database's `user_version`.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteStore> {
+        SqliteStore::with_connection(Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> Result<SqliteStore> {
+        SqliteStore::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut conn: Connection) -> Result<SqliteStore> {
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        if version > SCHEMA_VERSION {
+            bail!("Database has schema version {}, but only {} is supported",
+                  version, SCHEMA_VERSION);
+        }
+
+        let tx = conn.transaction()?;
+        if version < 1 {
+            tx.execute_batch(
+                "CREATE TABLE sessions (
+                     id TEXT PRIMARY KEY,
+                     count TEXT NOT NULL,
+                     last_user TEXT,
+                     high_score TEXT NOT NULL
+                 );
+                 CREATE TABLE user_stats (
+                     session TEXT NOT NULL REFERENCES sessions(id),
+                     user TEXT NOT NULL,
+                     correct INTEGER NOT NULL,
+                     failures INTEGER NOT NULL,
+                     PRIMARY KEY (session, user)
+                 );")?;
+        }
+        if version < 2 {
+            tx.execute_batch(
+                "ALTER TABLE sessions ADD COLUMN run INTEGER NOT NULL DEFAULT 0;
+                 ALTER TABLE sessions ADD COLUMN best_run INTEGER NOT NULL DEFAULT 0;")?;
+            if version == 1 {
+                // As in runs_from_v1
+                tx.execute_batch(
+                    "UPDATE sessions SET run = MAX(CAST(count AS INTEGER), 0),
+                                         best_run = MAX(CAST(high_score AS INTEGER), 0);")?;
+            }
+        }
+        if version < 3 {
+            tx.execute_batch(
+                "ALTER TABLE user_stats ADD COLUMN streak INTEGER NOT NULL DEFAULT 0;
+                 ALTER TABLE user_stats ADD COLUMN longest_streak INTEGER NOT NULL DEFAULT 0;
+                 ALTER TABLE user_stats ADD COLUMN best_size INTEGER NOT NULL DEFAULT 0;
+                 ALTER TABLE user_stats ADD COLUMN best_expr TEXT;
+                 CREATE TABLE user_ops (
+                     session TEXT NOT NULL REFERENCES sessions(id),
+                     user TEXT NOT NULL,
+                     op TEXT NOT NULL,
+                     count INTEGER NOT NULL,
+                     PRIMARY KEY (session, user, op)
+                 );")?;
+        }
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        tx.commit()?;
+
+        Ok(SqliteStore { conn: Mutex::new(conn) })
+    }
+}
+
+fn parse_num(s: String) -> Result<Num> {
+    s.parse().map_err(|_| simple_error!("Bad number {:?} in database", s).into())
+}
+
+impl Store for SqliteStore {
+    fn load(&self, session: &str) -> Result<Option<GameState>> {
+        let conn = self.conn.lock().unwrap();
+        let row = conn.query_row(
+            "SELECT count, last_user, run, high_score, best_run FROM sessions WHERE id = ?1",
+            [session],
+            |r| Ok((r.get::<_, String>(0)?, r.get(1)?, r.get(2)?, r.get::<_, String>(3)?, r.get(4)?)))
+            .optional()?;
+        let (count, last_user, run, high_score, best_run) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+
+        let mut stmt = conn.prepare(
+            "SELECT user, correct, failures, streak, longest_streak, best_size, best_expr
+             FROM user_stats WHERE session = ?1")?;
+        let mut users: BTreeMap<String, UserStats> = stmt.query_map([session], |r| {
+            Ok((r.get(0)?, UserStats {
+                correct: r.get(1)?,
+                failures: r.get(2)?,
+                streak: r.get(3)?,
+                longest_streak: r.get(4)?,
+                best_size: r.get(5)?,
+                best_expr: r.get(6)?,
+                ops: BTreeMap::new(),
+            }))
+        })?.collect::<rusqlite::Result<_>>()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT user, op, count FROM user_ops WHERE session = ?1")?;
+        let ops = stmt.query_map([session], |r| {
+            Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?, r.get::<_, u64>(2)?))
+        })?;
+        for op in ops {
+            let (user, op, count) = op?;
+            users.entry(user).or_default().ops.insert(op, count);
+        }
+
+        Ok(Some(GameState {
+            count: parse_num(count)?,
+            last_user,
+            run,
+            high_score: parse_num(high_score)?,
+            best_run,
+            stats: Stats { users },
+        }))
+    }
+
+    fn save(&self, session: &str, state: &GameState) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        tx.execute(
+            "INSERT INTO sessions (id, count, last_user, run, high_score, best_run)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (id) DO UPDATE SET
+                 count = excluded.count,
+                 last_user = excluded.last_user,
+                 run = excluded.run,
+                 high_score = excluded.high_score,
+                 best_run = excluded.best_run",
+            params![session, state.count.to_string(), state.last_user, state.run,
+                    state.high_score.to_string(), state.best_run])?;
+        tx.execute("DELETE FROM user_stats WHERE session = ?1", [session])?;
+        tx.execute("DELETE FROM user_ops WHERE session = ?1", [session])?;
+        for (user, stats) in state.stats.users.iter() {
+            tx.execute(
+                "INSERT INTO user_stats (session, user, correct, failures, streak,
+                                         longest_streak, best_size, best_expr)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![session, user, stats.correct, stats.failures, stats.streak,
+                        stats.longest_streak, stats.best_size, stats.best_expr])?;
+            for (op, count) in stats.ops.iter() {
+                tx.execute(
+                    "INSERT INTO user_ops (session, user, op, count) VALUES (?1, ?2, ?3, ?4)",
+                    params![session, user, op, count])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::to_num;
+    use crate::game::CountingSession;
+
+    fn played() -> GameState {
+        let mut game = CountingSession::new(Default::default());
+        for (user, msg) in [("a", "1"), ("b", "2"), ("a", "3"), ("b", "five"), ("a", "1"), ("b", "4/2")] {
+            game.submit(user, msg);
+        }
+        game.state().clone()
+    }
+
+    fn check_store(store: &dyn Store) {
+        let state = played();
+        assert_eq!(store.load("chan").unwrap(), None);
+
+        store.save("chan", &state).unwrap();
+        store.save("other", &GameState::new(&Default::default())).unwrap();
+        assert_eq!(store.load("chan").unwrap(), Some(state.clone()));
+
+        let mut state = state;
+        state.stats.users.remove("b");
+        state.last_user = None;
+        store.save("chan", &state).unwrap();
+        assert_eq!(store.load("chan").unwrap(), Some(state));
+        assert!(store.load("other").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_stores() {
+        let dir = tempfile::tempdir().unwrap();
+
+        check_store(&MemoryStore::new());
+        check_store(&JsonStore::open(dir.path().join("state.json")).unwrap());
+        check_store(&SqliteStore::in_memory().unwrap());
+
+        // State survives reopening
+        let path = dir.path().join("state.db");
+        SqliteStore::open(&path).unwrap().save("chan", &played()).unwrap();
+        assert_eq!(SqliteStore::open(&path).unwrap().load("chan").unwrap(), Some(played()));
+        let json = JsonStore::open(dir.path().join("state.json")).unwrap();
+        assert!(json.load("chan").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = dir.path().join("state.json");
+        fs::write(&path, r#"{"version": 99, "sessions": {}}"#).unwrap();
+        assert!(JsonStore::open(&path).is_err());
+
+        let path = dir.path().join("state.db");
+        Connection::open(&path).unwrap().pragma_update(None, "user_version", 99).unwrap();
+        assert!(SqliteStore::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_migration() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = dir.path().join("v1.json");
+        fs::write(&path, r#"{"version": 1, "sessions": {"chan": {
+            "count": "12", "last_user": "a", "high_score": "20", "users": {}}}}"#).unwrap();
+        let store = JsonStore::open(&path).unwrap();
+        let state = store.load("chan").unwrap().unwrap();
+        assert_eq!((&state.count, state.run, state.best_run), (&to_num(12), 12, 20));
+
+        // Saving anything brings the whole file up to date
+        store.save("other", &GameState::new(&Default::default())).unwrap();
+        let file: JsonFile = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(file.version, SCHEMA_VERSION);
+        assert_eq!(store.load("chan").unwrap(), Some(state));
+
+        let path = dir.path().join("v1.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE sessions (id TEXT PRIMARY KEY, count TEXT NOT NULL,
+                                    last_user TEXT, high_score TEXT NOT NULL);
+             CREATE TABLE user_stats (session TEXT NOT NULL REFERENCES sessions(id),
+                                      user TEXT NOT NULL, correct INTEGER NOT NULL,
+                                      failures INTEGER NOT NULL, PRIMARY KEY (session, user));
+             INSERT INTO sessions VALUES ('chan', '12', 'a', '20');
+             PRAGMA user_version = 1;").unwrap();
+        drop(conn);
+        let state = SqliteStore::open(&path).unwrap().load("chan").unwrap().unwrap();
+        assert_eq!((state.high_score, state.run, state.best_run), (to_num(20), 12, 20));
+    }
+}
@@ -1,15 +1,107 @@
+use crate::game::{Event, GameRules, Outcome};
+use crate::ast::Span;
+use crate::mode::ModeSpec;
+use crate::parse::Candidate;
+use crate::rules::RuleSet;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Request {
     pub message: String,
+    /// On connections that keep state, a variable to bind the result
+    /// to for later messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub set: Option<String>,
+}
+
+/// How a message was read: the parse `/eval` would evaluate, where in
+/// the message it came from, and every candidate it was chosen from,
+/// best first.
+#[derive(Serialize, Debug)]
+pub struct ParseResponse<'a> {
+    pub best: Option<&'a Candidate>,
+    pub span: Option<Span>,
+    pub candidates: &'a [Candidate],
+}
+
+/// One message in a count, and whether it counted.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecordRequest {
+    pub user: String,
+    pub message: String,
+    pub accepted: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum Response {
     Good { val: Option<String> },
-    Bad { message: String },
+    /// `code` says what went wrong in a way clients can match on, where
+    /// there's one
+    Bad {
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
+    },
+}
+
+/// Starts a counting session. Rules left out take their defaults.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateSessionRequest {
+    pub id: String,
+    #[serde(default)]
+    pub mode: ModeSpec,
+    pub allow_consecutive: Option<bool>,
+    pub reset_on_wrong: Option<bool>,
+    pub reset_on_consecutive: Option<bool>,
+    pub reset_on_no_number: Option<bool>,
+    /// How numbers may be written
+    pub rules: Option<RuleSet>,
+}
+
+impl CreateSessionRequest {
+    /// The requested rules, with anything not given taken from
+    /// `default`.
+    pub fn rules(&self, default: &GameRules) -> Result<GameRules> {
+        let default = default.clone();
+        Ok(GameRules {
+            mode: self.mode.build()?,
+            allow_consecutive: self.allow_consecutive.unwrap_or(default.allow_consecutive),
+            reset_on_wrong: self.reset_on_wrong.unwrap_or(default.reset_on_wrong),
+            reset_on_consecutive: self.reset_on_consecutive.unwrap_or(default.reset_on_consecutive),
+            reset_on_no_number: self.reset_on_no_number.unwrap_or(default.reset_on_no_number),
+            expr_rules: self.rules.clone().unwrap_or(default.expr_rules),
+            ..default
+        })
+    }
+}
+
+/// A message to a counting session.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubmitRequest {
+    pub user: String,
+    pub message: String,
+}
+
+/// Replies from the counting session routes. Numbers are in their
+/// decimal "n" or "n/d" form.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum GameResponse {
+    Created { id: String },
+    Outcomes { outcomes: Vec<Outcome> },
+    #[serde(rename_all = "camelCase")]
+    Count { count: String, expected: String, last_user: Option<String>, run: u64 },
+    #[serde(rename_all = "camelCase")]
+    HighScore { high_score: String, best_run: u64 },
+    History { events: Vec<Event> },
+    /// `code` is as for `Response::Bad`
+    Bad {
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
+    },
 }
 
 pub type Result<A> = std::result::Result<A, Box<dyn std::error::Error>>;
@@ -13,11 +13,21 @@ pub enum Token<'input> {
     #[token("minus", ignore(case))]
     #[token("-")]
     Minus,
+    // A "-" written right before a number, read as its sign. Only
+    // `with_signs` makes these.
+    NegSign,
     #[token("times", ignore(case))]
     #[token("*")]
     Times,
     #[token("/")]
+    #[regex(r"divided\s+by", ignore(case))]
     Slash,
+    #[token("//")]
+    DSlash,
+    #[token("%")]
+    #[token("mod", ignore(case))]
+    #[token("modulo", ignore(case))]
+    Percent,
     #[token("^")]
     Hat,
     #[token("!")]
@@ -26,6 +36,8 @@ pub enum Token<'input> {
     LParen,
     #[token(")")]
     RParen,
+    #[token(",")]
+    Comma,
     #[token("&")]
     #[token("and", ignore(case))]
     And,
@@ -39,15 +51,37 @@ pub enum Token<'input> {
     LShift,
     #[token(">>")]
     RShift,
+    #[token("==")]
+    #[token("equals", ignore(case))]
+    EqEq,
+    #[token("!=")]
+    NotEq,
+    #[token("<")]
+    Lt,
+    #[token("<=")]
+    Le,
+    #[token(">")]
+    Gt,
+    #[token(">=")]
+    Ge,
+    // Rolls too big for an i64 come out as Unknown
     #[regex(r"\d*(d|D)\d+", |lex| parse_roll(lex.slice()))]
     Roll((i64, i64)),
-    #[regex(r"-?([0-9]+(\.[0-9]*)?|\.[0-9]+)", |lex| parse_decimal(lex.slice()))]
-    #[regex(r"-?0x([0-9a-fA-F]+(\.[0-9a-fA-F]*)?|\.[0-9a-fA-F]+)", |lex| parse_radix_prefixed(lex.slice(), 16))]
-    #[regex(r"-?0o([0-7]+(\.[0-7]*)?|\.[0-7]+)", |lex| parse_radix_prefixed(lex.slice(), 8))]
-    #[regex(r"-?0b([01]+(\.[01]*)?|\.[01]+)", |lex| parse_radix_prefixed(lex.slice(), 2))]
+    #[regex(r"([0-9]+(\.[0-9]*)?|\.[0-9]+)", |lex| parse_decimal(lex.slice()))]
     Digits(Num),
+    #[regex(r"0x([0-9a-fA-F]+(\.[0-9a-fA-F]*)?|\.[0-9a-fA-F]+)", |lex| parse_radix_prefixed(lex.slice(), 16))]
+    HexDigits(Num),
+    #[regex(r"0o([0-7]+(\.[0-7]*)?|\.[0-7]+)", |lex| parse_radix_prefixed(lex.slice(), 8))]
+    OctDigits(Num),
+    #[regex(r"0b([01]+(\.[01]*)?|\.[01]+)", |lex| parse_radix_prefixed(lex.slice(), 2))]
+    BinDigits(Num),
     #[regex(r"[a-zA-Z][a-zA-Z0-9_]*", |lex| lex.slice())]
     Var(&'input str),
+    // A name directly followed by "(" is a function call
+    #[regex(r"[a-zA-Z][a-zA-Z0-9_]*\(", |lex| &lex.slice()[..lex.slice().len() - 1])]
+    Call(&'input str),
+
+    #[token("negative", ignore(case))] Negative,
 
     #[token("zero", ignore(case))] Zero,
     #[token("one", ignore(case))] One,
@@ -88,30 +122,23 @@ pub enum Token<'input> {
     Unknown,
 }
 
-fn parse_roll(roll: &str) -> (i64, i64) {
+fn parse_roll(roll: &str) -> Option<(i64, i64)> {
     lazy_static! {
         static ref REGEX: Regex = Regex::new(r"^(\d*)(d|D)(\d+)$").unwrap();
     }
 
     let captures = REGEX.captures(roll).unwrap();
+    let n = if captures[1].is_empty() { 1 } else { captures[1].parse().ok()? };
 
-    return (captures[1].parse().unwrap_or(1), captures[3].parse().unwrap());
+    Some((n, captures[3].parse().ok()?))
 }
 
 fn parse_radix_prefixed(istring: &str, radix: u32) -> Num {
-    let negative = istring.chars().next().unwrap() == '-';
-    let start = if negative { 3 } else { 2 };
-    let sign = to_num(if negative { -1 } else { 1 });
-
-    sign * parse_radix(&istring.as_bytes()[start..], radix)
+    parse_radix(&istring.as_bytes()[2..], radix)
 }
 
 fn parse_decimal(istring: &str) -> Num {
-    let negative = istring.chars().next().unwrap() == '-';
-    let start = if negative { 1 } else { 0 };
-    let sign = to_num(if negative { -1 } else { 1 });
-
-    sign * parse_radix(&istring.as_bytes()[start..], 10)
+    parse_radix(istring.as_bytes(), 10)
 }
 fn parse_radix(istring: &[u8], radix: u32) -> Num {
     lazy_static! {
@@ -123,12 +150,12 @@ fn parse_radix(istring: &[u8], radix: u32) -> Num {
     println!("Captures: {:?}", captures);
 
     let int_part: Num = captures.get(1).map(
-        |mtch| BigInt::parse_bytes(&mtch.as_str().as_bytes(), radix).unwrap().into())
+        |mtch| BigInt::parse_bytes(mtch.as_str().as_bytes(), radix).unwrap().into())
         .unwrap_or(to_num(0));
     let frac_part: Num = captures.get(3)
         .filter(|mtch| mtch.start() != mtch.end())
         .map(|mtch| {
-            let frac: Num = BigInt::parse_bytes(&mtch.as_str().as_bytes(), radix).unwrap().into();
+            let frac: Num = BigInt::parse_bytes(mtch.as_str().as_bytes(), radix).unwrap().into();
             let length = mtch.as_str().chars().count();
             frac / (to_num(radix as i64).pow(length as i32))
         })
@@ -138,6 +165,7 @@ fn parse_radix(istring: &[u8], radix: u32) -> Num {
 }
 
 // lalrpop takes an Iterator with item = Result<(Loc, Tok, Loc), LexError>
+pub type SpannedToken<'input> = Result<(usize, Token<'input>, usize), String>;
 
 pub struct TokenLexer<'input> {
     pub lexer: logos::Lexer<'input, Token<'input>>,
@@ -150,7 +178,7 @@ impl<'input> TokenLexer<'input> {
 }
 
 impl<'input> Iterator for TokenLexer<'input> {
-    type Item = Result<(usize, Token<'input>, usize), String>;
+    type Item = SpannedToken<'input>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.lexer.next() {
@@ -163,6 +191,22 @@ impl<'input> Iterator for TokenLexer<'input> {
     }
 }
 
+/// `tokens`, as lexed from `line`, with each "-" written right before a
+/// number made a `Token::NegSign`. Postfix and prefix need these for
+/// negative operands, as in `3 -2 *`, but infix reads `5 -3` as a
+/// subtraction, so they're only worth trying once the tokens as lexed
+/// don't parse.
+pub fn with_signs<'input>(line: &'input str, tokens: &[SpannedToken<'input>]) -> Vec<SpannedToken<'input>> {
+    tokens.iter().enumerate()
+        .map(|(i, tok)| match (tok, tokens.get(i + 1)) {
+            (Ok((s, Token::Minus, e)),
+             Some(Ok((next, Token::Digits(_) | Token::HexDigits(_) | Token::OctDigits(_) | Token::BinDigits(_), _))))
+                if &line[*s..*e] == "-" && e == next => Ok((*s, Token::NegSign, *e)),
+            _ => tok.clone(),
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -176,10 +220,43 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_lexer_minus() {
+        let toks: Vec<_> = Token::lexer("5 -3").collect();
+        assert_eq!(toks, vec![Token::Digits(to_num(5)), Token::Minus, Token::Digits(to_num(3))]);
+    }
+
+    #[test]
+    fn test_with_signs() {
+        let line = "3 -2 - 1 minus 4";
+        let tokens: Vec<_> = TokenLexer::new(line).collect();
+        let toks: Vec<_> = with_signs(line, &tokens).into_iter().map(|t| t.unwrap().1).collect();
+        assert_eq!(toks, vec![Token::Digits(to_num(3)), Token::NegSign, Token::Digits(to_num(2)),
+                              Token::Minus, Token::Digits(to_num(1)), Token::Minus, Token::Digits(to_num(4))]);
+    }
+
+    #[test]
+    fn test_lexer_words() {
+        let toks: Vec<_> = Token::lexer("6 Divided  by 2 modulo 4 equals 1 // 1 != 0").collect();
+        assert_eq!(toks, vec![Token::Digits(to_num(6)), Token::Slash, Token::Digits(to_num(2)),
+                              Token::Percent, Token::Digits(to_num(4)), Token::EqEq,
+                              Token::Digits(to_num(1)), Token::DSlash, Token::Digits(to_num(1)),
+                              Token::NotEq, Token::Digits(to_num(0))]);
+    }
+
+    #[test]
+    fn test_lexer_radix() {
+        let toks: Vec<_> = Token::lexer("0x1f 0o17 0b101 10").collect();
+        assert_eq!(toks, vec![Token::HexDigits(to_num(31)), Token::OctDigits(to_num(15)),
+                              Token::BinDigits(to_num(5)), Token::Digits(to_num(10))]);
+    }
+
     #[test]
     fn test_parse_roll() {
-        assert_eq!(parse_roll("3d7"), (3, 7));
-        assert_eq!(parse_roll("d8"), (1, 8));
+        assert_eq!(parse_roll("3d7"), Some((3, 7)));
+        assert_eq!(parse_roll("d8"), Some((1, 8)));
+        assert_eq!(parse_roll("1d99999999999999999999"), None);
+        assert_eq!(parse_roll("99999999999999999999d6"), None);
     }
 
     #[test]
@@ -194,11 +271,7 @@ mod tests {
         assert_eq!(parse_decimal("1.5"), to_num(3) / to_num(2));
         assert_eq!(parse_decimal(".05"), to_num(1) / to_num(20));
         assert_eq!(parse_decimal("50."), to_num(50));
-        assert_eq!(parse_decimal("-50."), to_num(-50));
         assert_eq!(parse_radix_prefixed("0x10.1", 16), to_num(257) / to_num(16));
-        assert_eq!(parse_radix_prefixed("-0x10.1", 16), to_num(-257) / to_num(16));
-        assert_eq!(parse_radix_prefixed("-0x.1", 16), to_num(-1) / to_num(16));
-        assert_eq!(parse_radix_prefixed("-0x1.", 16), to_num(-1));
         assert_eq!(parse_radix_prefixed("0x1.", 16), to_num(1));
     }
 }